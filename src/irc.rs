@@ -17,12 +17,32 @@
 
 #![allow(dead_code)]
 
+pub use config::Config;
 pub use service::Service;
 pub use thread::Listener;
+pub use thread::Timer;
 pub use thread::Worker;
 
+pub const AWAY_LENGTH: usize = 390;
 pub const BUFFER_SIZE: usize = 512;
-
+pub const CLIENT_TAG_SIZE: usize = 4094;
+pub const CHANNEL_LENGTH: usize = 50;
+pub const CHANNEL_TYPES: &str = "#&";
+pub const KICK_LENGTH: usize = 390;
+pub const MAX_PARAMETERS: usize = 15;
+pub const NICK_LENGTH: usize = 30;
+pub const TAG_BUFFER_SIZE: usize = 8191;
+pub const TOPIC_LENGTH: usize = 390;
+pub const USER_LENGTH: usize = 10;
+
+mod capability;
+mod casemapping;
+mod channel;
+mod config;
+mod mask;
 mod message;
+mod motd;
 mod service;
+mod session;
 mod thread;
+mod whowas;
@@ -0,0 +1,96 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+pub struct Capability {
+    name: String,
+    value: String,
+}
+
+impl Capability {
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    // Returns the capability as advertised by CAP LS. Values are only sent to
+    // clients that negotiated version 302 or later.
+    pub fn string(&self, version: u32) -> String {
+        if version >= 302 && !self.value.is_empty() {
+            format!("{:}={:}", self.name, self.value)
+        } else {
+            self.name.clone()
+        }
+    }
+
+    pub fn value(&self) -> &String {
+        &self.value
+    }
+
+    pub fn new(name: &str, value: &str) -> Capability {
+        Capability {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+// Capabilities is the registry of IRCv3 capabilities the server offers to
+// clients, in the order they are advertised.
+pub struct Capabilities {
+    capabilities: Vec<Capability>,
+}
+
+impl Capabilities {
+    // Add or replace a capability.
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.capabilities.push(Capability::new(name, value));
+    }
+
+    pub fn capabilities(&self) -> &Vec<Capability> {
+        &self.capabilities
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Capability> {
+        self.capabilities
+            .iter()
+            .find(|capability| capability.name() == name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.capabilities.len();
+        self.capabilities
+            .retain(|capability| capability.name() != name);
+        len != self.capabilities.len()
+    }
+
+    pub fn new() -> Capabilities {
+        let mut capabilities = Capabilities {
+            capabilities: Vec::new(),
+        };
+        capabilities.add("away-notify", "");
+        capabilities.add("cap-notify", "");
+        capabilities.add("invite-notify", "");
+        capabilities.add("message-tags", "");
+        capabilities.add("multi-prefix", "");
+        capabilities.add("server-time", "");
+        capabilities
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use unicode_normalization::UnicodeNormalization;
+
+// Casemapping decides which nick and channel names are considered equal. Every
+// name index folds names with the server's casemapping before using them as a
+// key, and the casemapping in use is advertised to clients in ISUPPORT.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Casemapping {
+    // Only A-Z and a-z are equal.
+    Ascii,
+    // As ascii, with []\~ being the uppercase forms of {}|^.
+    Rfc1459,
+    // As rfc1459, but ~ and ^ are different characters.
+    StrictRfc1459,
+    // The PRECIS UsernameCaseMapped profile, which allows Unicode nicks.
+    Rfc7613,
+}
+
+impl Casemapping {
+    // Fold a name so that names equal under this casemapping are identical.
+    pub fn fold(&self, name: &str) -> String {
+        match self {
+            Casemapping::Ascii => name.to_ascii_lowercase(),
+            Casemapping::Rfc1459 => name
+                .chars()
+                .map(|c| match c {
+                    '[' => '{',
+                    ']' => '}',
+                    '\\' => '|',
+                    '~' => '^',
+                    _ => c.to_ascii_lowercase(),
+                })
+                .collect(),
+            Casemapping::StrictRfc1459 => name
+                .chars()
+                .map(|c| match c {
+                    '[' => '{',
+                    ']' => '}',
+                    '\\' => '|',
+                    _ => c.to_ascii_lowercase(),
+                })
+                .collect(),
+            // Map fullwidth and halfwidth characters to their normal width,
+            // lowercase, then normalize to NFC.
+            Casemapping::Rfc7613 => {
+                let mut mapped = String::new();
+                for c in name.chars() {
+                    if wide(c) {
+                        mapped.extend(c.nfkc());
+                    } else {
+                        mapped.push(c);
+                    }
+                }
+                mapped.to_lowercase().nfc().collect()
+            }
+        }
+    }
+
+    // Returns the name advertised in ISUPPORT.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Casemapping::Ascii => "ascii",
+            Casemapping::Rfc1459 => "rfc1459",
+            Casemapping::StrictRfc1459 => "strict-rfc1459",
+            Casemapping::Rfc7613 => "rfc7613",
+        }
+    }
+
+    // Returns true when nicks may contain characters outside of ASCII.
+    pub fn unicode(&self) -> bool {
+        *self == Casemapping::Rfc7613
+    }
+
+    pub fn from_name(name: &str) -> Option<Casemapping> {
+        match name {
+            "ascii" => Some(Casemapping::Ascii),
+            "rfc1459" => Some(Casemapping::Rfc1459),
+            "strict-rfc1459" => Some(Casemapping::StrictRfc1459),
+            "rfc7613" => Some(Casemapping::Rfc7613),
+            _ => None,
+        }
+    }
+}
+
+// Returns true for the ideographic space and the Halfwidth and Fullwidth Forms
+// block, which have <wide> or <narrow> compatibility decompositions.
+fn wide(c: char) -> bool {
+    c == '\u{3000}' || ('\u{ff01}'..='\u{ffee}').contains(&c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii() {
+        let ascii = Casemapping::Ascii;
+        assert_eq!(ascii.fold("NiCk-09_"), "nick-09_");
+        assert_eq!(ascii.fold("[]\\~"), "[]\\~");
+        assert_eq!(ascii.fold("{}|^"), "{}|^");
+        assert_eq!(ascii.fold("\u{c9}"), "\u{c9}");
+    }
+
+    #[test]
+    fn rfc1459() {
+        let rfc1459 = Casemapping::Rfc1459;
+        assert_eq!(rfc1459.fold("NiCk-09_"), "nick-09_");
+        for (upper, lower) in ["[{", "]}", "\\|", "~^"].iter().map(|p| p.split_at(1)) {
+            assert_eq!(rfc1459.fold(upper), lower);
+            assert_eq!(rfc1459.fold(lower), lower);
+        }
+        assert_eq!(rfc1459.fold("Foo[Bar]\\~"), rfc1459.fold("fOO{bAR}|^"));
+        assert_eq!(rfc1459.fold("\u{c9}"), "\u{c9}");
+    }
+
+    #[test]
+    fn strict_rfc1459() {
+        let strict = Casemapping::StrictRfc1459;
+        assert_eq!(strict.fold("NiCk-09_"), "nick-09_");
+        for (upper, lower) in ["[{", "]}", "\\|"].iter().map(|p| p.split_at(1)) {
+            assert_eq!(strict.fold(upper), lower);
+            assert_eq!(strict.fold(lower), lower);
+        }
+        assert_eq!(strict.fold("~"), "~");
+        assert_eq!(strict.fold("^"), "^");
+        assert_eq!(strict.fold("Foo[Bar]\\"), strict.fold("fOO{bAR}|"));
+        assert_ne!(strict.fold("a~"), strict.fold("a^"));
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::casemapping::Casemapping;
+use crate::irc::CHANNEL_TYPES;
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+// Channel modes by the kind of parameter they take, as advertised in the
+// CHANMODES ISUPPORT token. List modes add or remove a mask, key modes always
+// take a parameter, limit modes only take one when set and flag modes never do.
+pub const FLAG_MODES: &str = "imnpst";
+pub const KEY_MODES: &str = "k";
+pub const LIMIT_MODES: &str = "l";
+pub const LIST_MODES: &str = "beI";
+
+// Prefix modes and the symbols shown before members' nicks, from the highest
+// rank to the lowest.
+pub const PREFIXES: [(char, char); 5] =
+    [('q', '~'), ('a', '&'), ('o', '@'), ('h', '%'), ('v', '+')];
+
+pub struct Channel {
+    casemapping: Casemapping,
+    created: u64,
+    invites: HashMap<u64, Instant>,
+    key: String,
+    limit: usize,
+    lists: HashMap<char, Vec<ListEntry>>,
+    members: BTreeSet<u64>,
+    modes: BTreeSet<char>,
+    name: String,
+    statuses: HashMap<u64, BTreeSet<char>>,
+    topic: String,
+    topic_setter: String,
+    topic_time: u64,
+}
+
+impl Channel {
+    // Add a mask to one of the channel's lists. Returns false if the mask was
+    // already there, compared using the casemapping.
+    pub fn add_entry(&mut self, list: char, entry: ListEntry) -> bool {
+        let casemapping = self.casemapping;
+        let mask = casemapping.fold(entry.mask());
+        let entries = self.lists.entry(list).or_default();
+        if entries.iter().any(|e| casemapping.fold(e.mask()) == mask) {
+            return false;
+        }
+        entries.push(entry);
+        true
+    }
+
+    // Let a session join past +i until the invite expires or is used.
+    pub fn add_invite(&mut self, id: u64, time: Instant) {
+        self.invites.insert(id, time);
+    }
+
+    pub fn add_member(&mut self, id: u64) {
+        self.members.insert(id);
+    }
+
+    pub fn add_mode(&mut self, mode: char) {
+        self.modes.insert(mode);
+    }
+
+    pub fn add_status(&mut self, id: u64, mode: char) {
+        self.statuses.entry(id).or_default().insert(mode);
+    }
+
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    pub fn entries(&self, list: char) -> &[ListEntry] {
+        match self.lists.get(&list) {
+            Some(entries) => entries,
+            None => &[],
+        }
+    }
+
+    // Forget invites older than expiry.
+    pub fn expire_invites(&mut self, expiry: Duration) {
+        self.invites.retain(|_id, time| time.elapsed() < expiry);
+    }
+
+    pub fn has_member(&self, id: u64) -> bool {
+        self.members.contains(&id)
+    }
+
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.modes.contains(&mode)
+    }
+
+    pub fn has_status(&self, id: u64, mode: char) -> bool {
+        match self.statuses.get(&id) {
+            Some(statuses) => statuses.contains(&mode),
+            None => false,
+        }
+    }
+
+    // Returns true if the session holds an invite younger than expiry.
+    pub fn invited(&self, id: u64, expiry: Duration) -> bool {
+        match self.invites.get(&id) {
+            Some(time) => time.elapsed() < expiry,
+            None => false,
+        }
+    }
+
+    pub fn key(&self) -> &String {
+        &self.key
+    }
+
+    // Returns the most members allowed to join, or 0 when there is no limit.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn members(&self) -> &BTreeSet<u64> {
+        &self.members
+    }
+
+    // Returns the flag modes that are set.
+    pub fn modes(&self) -> String {
+        self.modes.iter().collect()
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    // Returns the symbols to show before a member's nick, either every one
+    // they hold or only the highest.
+    pub fn prefix(&self, id: u64, all: bool) -> String {
+        let symbols = PREFIXES
+            .iter()
+            .filter(|(mode, _symbol)| self.has_status(id, *mode))
+            .map(|(_mode, symbol)| *symbol);
+        if all {
+            symbols.collect()
+        } else {
+            symbols.take(1).collect()
+        }
+    }
+
+    // Returns how highly ranked a member is, from 0 for no prefix mode up to
+    // the number of prefix modes for the highest.
+    pub fn rank(&self, id: u64) -> usize {
+        for (i, (mode, _symbol)) in PREFIXES.iter().enumerate() {
+            if self.has_status(id, *mode) {
+                return PREFIXES.len() - i;
+            }
+        }
+        0
+    }
+
+    // Remove a mask from one of the channel's lists. Returns the mask as it
+    // was stored if it was there.
+    pub fn remove_entry(&mut self, list: char, mask: &str) -> Option<String> {
+        let casemapping = self.casemapping;
+        let mask = casemapping.fold(mask);
+        let entries = self.lists.get_mut(&list)?;
+        let i = entries
+            .iter()
+            .position(|e| casemapping.fold(e.mask()) == mask)?;
+        Some(entries.remove(i).mask().clone())
+    }
+
+    pub fn remove_invite(&mut self, id: u64) {
+        self.invites.remove(&id);
+    }
+
+    pub fn remove_member(&mut self, id: u64) {
+        self.members.remove(&id);
+        self.statuses.remove(&id);
+    }
+
+    pub fn remove_mode(&mut self, mode: char) {
+        self.modes.remove(&mode);
+    }
+
+    pub fn remove_status(&mut self, id: u64, mode: char) {
+        if let Some(statuses) = self.statuses.get_mut(&id) {
+            statuses.remove(&mode);
+        }
+    }
+
+    pub fn set_key(&mut self, key: &str) {
+        self.key = key.to_string();
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    pub fn set_topic(&mut self, topic: &str, setter: &str, time: u64) {
+        self.topic = topic.to_string();
+        self.topic_setter = setter.to_string();
+        self.topic_time = time;
+    }
+
+    // Returns the NAMES symbol for the channel, @ when secret, * when private
+    // and = otherwise.
+    pub fn symbol(&self) -> &'static str {
+        if self.has_mode('s') {
+            "@"
+        } else if self.has_mode('p') {
+            "*"
+        } else {
+            "="
+        }
+    }
+
+    pub fn topic(&self) -> &String {
+        &self.topic
+    }
+
+    pub fn topic_setter(&self) -> &String {
+        &self.topic_setter
+    }
+
+    pub fn topic_time(&self) -> u64 {
+        self.topic_time
+    }
+
+    pub fn new(name: &str, created: u64, casemapping: Casemapping) -> Channel {
+        Channel {
+            casemapping,
+            created,
+            invites: HashMap::new(),
+            key: String::new(),
+            limit: 0,
+            lists: HashMap::new(),
+            members: BTreeSet::new(),
+            modes: BTreeSet::new(),
+            name: name.to_string(),
+            statuses: HashMap::new(),
+            topic: String::new(),
+            topic_setter: String::new(),
+            topic_time: 0,
+        }
+    }
+}
+
+// ListEntry is a mask on one of a channel's ban, exception or invite exception
+// lists along with who set it and when.
+pub struct ListEntry {
+    mask: String,
+    setter: String,
+    time: u64,
+}
+
+impl ListEntry {
+    pub fn mask(&self) -> &String {
+        &self.mask
+    }
+
+    pub fn setter(&self) -> &String {
+        &self.setter
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    pub fn new(mask: &str, setter: &str, time: u64) -> ListEntry {
+        ListEntry {
+            mask: mask.to_string(),
+            setter: setter.to_string(),
+            time,
+        }
+    }
+}
+
+// Channels is the registry of every channel on the server keyed by its folded
+// name. A channel exists for as long as it has at least one member.
+pub struct Channels {
+    casemapping: Casemapping,
+    channels: HashMap<String, Channel>,
+}
+
+impl Channels {
+    pub fn channels(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.values()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Channel> {
+        self.channels.get(&self.casemapping.fold(name))
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Channel> {
+        self.channels.get_mut(&self.casemapping.fold(name))
+    }
+
+    // Returns the named channel, creating it if it does not exist yet.
+    pub fn get_or_create(&mut self, name: &str, time: u64) -> &mut Channel {
+        let casemapping = self.casemapping;
+        self.channels
+            .entry(casemapping.fold(name))
+            .or_insert_with(|| Channel::new(name, time, casemapping))
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    // Remove a member from a channel and destroy the channel once the last
+    // member has left. Returns false if id was not a member.
+    pub fn part(&mut self, name: &str, id: u64) -> bool {
+        let key = self.casemapping.fold(name);
+        let (parted, empty) = match self.channels.get_mut(&key) {
+            Some(channel) if channel.has_member(id) => {
+                channel.remove_member(id);
+                (true, channel.members().is_empty())
+            }
+            _ => (false, false),
+        };
+        if empty {
+            self.channels.remove(&key);
+        }
+        parted
+    }
+
+    pub fn new(casemapping: Casemapping) -> Channels {
+        Channels {
+            casemapping,
+            channels: HashMap::new(),
+        }
+    }
+}
+
+// Returns true when name starts with one of the channel types, so it should be
+// treated as a channel rather than a nick.
+pub fn is_channel(name: &str) -> bool {
+    name.starts_with(|c| CHANNEL_TYPES.contains(c))
+}
+
+// channel = ( "#" / "&" ) chanstring, where chanstring excludes NUL, BELL, CR,
+// LF, space, comma and colon.
+pub fn valid_channel(name: &str, channel_length: usize) -> bool {
+    is_channel(name)
+        && name.len() > 1
+        && name.len() <= channel_length
+        && !name.contains(['\0', '\x07', '\r', '\n', ' ', ',', ':'])
+}
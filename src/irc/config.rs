@@ -0,0 +1,332 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::casemapping::Casemapping;
+use crate::irc::{
+    AWAY_LENGTH, CHANNEL_LENGTH, KICK_LENGTH, NICK_LENGTH, TOPIC_LENGTH, USER_LENGTH,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Config holds the server settings that operators may want to change. Every
+// setting starts with a sensible default.
+pub struct Config {
+    admin_email: String,
+    admin_location: String,
+    admin_organization: String,
+    away_length: usize,
+    casemapping: Casemapping,
+    channel_length: usize,
+    description: String,
+    info: String,
+    invite_expiry: Duration,
+    kick_length: usize,
+    max_list: usize,
+    max_targets: usize,
+    modes: usize,
+    motd_path: String,
+    network: String,
+    nick_delay: Duration,
+    nick_length: usize,
+    operators: HashMap<String, String>,
+    ping_frequency: Duration,
+    ping_timeout: Duration,
+    prefix_modes: String,
+    registration_timeout: Duration,
+    sendq: usize,
+    topic_length: usize,
+    user_length: usize,
+    whowas_length: usize,
+}
+
+impl Config {
+    // Allow OPER with name and password to make a client an IRC operator.
+    pub fn add_operator(&mut self, name: &str, password: &str) {
+        self.operators
+            .insert(name.to_string(), password.to_string());
+    }
+
+    // Returns the contact address given by ADMIN.
+    pub fn admin_email(&self) -> &String {
+        &self.admin_email
+    }
+
+    // Returns where the server is, as given by ADMIN.
+    pub fn admin_location(&self) -> &String {
+        &self.admin_location
+    }
+
+    // Returns who runs the server, as given by ADMIN.
+    pub fn admin_organization(&self) -> &String {
+        &self.admin_organization
+    }
+
+    // Returns the longest away message a client may set.
+    pub fn away_length(&self) -> usize {
+        self.away_length
+    }
+
+    // Returns the casemapping used to compare nick and channel names.
+    pub fn casemapping(&self) -> Casemapping {
+        self.casemapping
+    }
+
+    // Returns the longest channel name that may be created.
+    pub fn channel_length(&self) -> usize {
+        self.channel_length
+    }
+
+    // Returns the short description of the server shown in WHOIS.
+    pub fn description(&self) -> &String {
+        &self.description
+    }
+
+    // Returns the text sent by INFO, one reply per line.
+    pub fn info(&self) -> &String {
+        &self.info
+    }
+
+    // Returns how long an INVITE lets its target past +i before it lapses.
+    pub fn invite_expiry(&self) -> Duration {
+        self.invite_expiry
+    }
+
+    // Returns the longest reason a KICK may give.
+    pub fn kick_length(&self) -> usize {
+        self.kick_length
+    }
+
+    // Returns the most masks each of a channel's ban, exception and invite
+    // exception lists may hold.
+    pub fn max_list(&self) -> usize {
+        self.max_list
+    }
+
+    // Returns the most targets a single PRIVMSG or NOTICE may be sent to.
+    pub fn max_targets(&self) -> usize {
+        self.max_targets
+    }
+
+    // Returns the most modes with a parameter that a single MODE may change.
+    pub fn modes(&self) -> usize {
+        self.modes
+    }
+
+    // Returns the file the message of the day is read from.
+    pub fn motd_path(&self) -> &String {
+        &self.motd_path
+    }
+
+    // Returns the name of the network this server belongs to.
+    pub fn network(&self) -> &String {
+        &self.network
+    }
+
+    // Returns how long a registered client must wait between nick changes.
+    pub fn nick_delay(&self) -> Duration {
+        self.nick_delay
+    }
+
+    // Returns the longest nick a client may use.
+    pub fn nick_length(&self) -> usize {
+        self.nick_length
+    }
+
+    // Returns the password for an operator name.
+    pub fn operator(&self, name: &str) -> Option<&String> {
+        self.operators.get(name)
+    }
+
+    // Returns how long a registered client may stay silent before it is sent
+    // a PING.
+    pub fn ping_frequency(&self) -> Duration {
+        self.ping_frequency
+    }
+
+    // Returns how long to wait for any reply to a PING before disconnecting.
+    pub fn ping_timeout(&self) -> Duration {
+        self.ping_timeout
+    }
+
+    // Returns which of the qaohv prefix modes channels support. Operator (o)
+    // should always be included as it is given to channel creators.
+    pub fn prefix_modes(&self) -> &String {
+        &self.prefix_modes
+    }
+
+    // Returns how long a client has to finish registering after connecting.
+    pub fn registration_timeout(&self) -> Duration {
+        self.registration_timeout
+    }
+
+    // Returns the most bytes that may be queued for a client before it is
+    // disconnected.
+    pub fn sendq(&self) -> usize {
+        self.sendq
+    }
+
+    pub fn set_admin_email(&mut self, admin_email: &str) {
+        self.admin_email = admin_email.to_string();
+    }
+
+    pub fn set_admin_location(&mut self, admin_location: &str) {
+        self.admin_location = admin_location.to_string();
+    }
+
+    pub fn set_admin_organization(&mut self, admin_organization: &str) {
+        self.admin_organization = admin_organization.to_string();
+    }
+
+    pub fn set_away_length(&mut self, away_length: usize) {
+        self.away_length = away_length;
+    }
+
+    pub fn set_casemapping(&mut self, casemapping: Casemapping) {
+        self.casemapping = casemapping;
+    }
+
+    pub fn set_channel_length(&mut self, channel_length: usize) {
+        self.channel_length = channel_length;
+    }
+
+    pub fn set_description(&mut self, description: &str) {
+        self.description = description.to_string();
+    }
+
+    pub fn set_info(&mut self, info: &str) {
+        self.info = info.to_string();
+    }
+
+    pub fn set_invite_expiry(&mut self, invite_expiry: Duration) {
+        self.invite_expiry = invite_expiry;
+    }
+
+    pub fn set_kick_length(&mut self, kick_length: usize) {
+        self.kick_length = kick_length;
+    }
+
+    pub fn set_max_list(&mut self, max_list: usize) {
+        self.max_list = max_list;
+    }
+
+    pub fn set_max_targets(&mut self, max_targets: usize) {
+        self.max_targets = max_targets;
+    }
+
+    pub fn set_modes(&mut self, modes: usize) {
+        self.modes = modes;
+    }
+
+    pub fn set_motd_path(&mut self, motd_path: &str) {
+        self.motd_path = motd_path.to_string();
+    }
+
+    pub fn set_network(&mut self, network: &str) {
+        self.network = network.to_string();
+    }
+
+    pub fn set_nick_delay(&mut self, nick_delay: Duration) {
+        self.nick_delay = nick_delay;
+    }
+
+    pub fn set_nick_length(&mut self, nick_length: usize) {
+        self.nick_length = nick_length;
+    }
+
+    pub fn set_ping_frequency(&mut self, ping_frequency: Duration) {
+        self.ping_frequency = ping_frequency;
+    }
+
+    pub fn set_ping_timeout(&mut self, ping_timeout: Duration) {
+        self.ping_timeout = ping_timeout;
+    }
+
+    pub fn set_prefix_modes(&mut self, prefix_modes: &str) {
+        self.prefix_modes = prefix_modes.to_string();
+    }
+
+    pub fn set_registration_timeout(&mut self, registration_timeout: Duration) {
+        self.registration_timeout = registration_timeout;
+    }
+
+    pub fn set_sendq(&mut self, sendq: usize) {
+        self.sendq = sendq;
+    }
+
+    pub fn set_topic_length(&mut self, topic_length: usize) {
+        self.topic_length = topic_length;
+    }
+
+    pub fn set_user_length(&mut self, user_length: usize) {
+        self.user_length = user_length;
+    }
+
+    pub fn set_whowas_length(&mut self, whowas_length: usize) {
+        self.whowas_length = whowas_length;
+    }
+
+    // Returns the longest topic a channel may have.
+    pub fn topic_length(&self) -> usize {
+        self.topic_length
+    }
+
+    // Returns the longest username a client may register with.
+    pub fn user_length(&self) -> usize {
+        self.user_length
+    }
+
+    // Returns how many former nicks WHOWAS remembers.
+    pub fn whowas_length(&self) -> usize {
+        self.whowas_length
+    }
+
+    pub fn new() -> Config {
+        Config {
+            admin_email: String::new(),
+            admin_location: String::new(),
+            admin_organization: String::new(),
+            away_length: AWAY_LENGTH,
+            casemapping: Casemapping::Rfc1459,
+            channel_length: CHANNEL_LENGTH,
+            description: "Platform IRC server".to_string(),
+            info: concat!(
+                "Platform IRC server\n",
+                "Copyright 2020 Jonathan Windle\n",
+                "Licensed under the GNU Affero General Public License version 3 or later"
+            )
+            .to_string(),
+            invite_expiry: Duration::from_secs(3600),
+            kick_length: KICK_LENGTH,
+            max_list: 100,
+            max_targets: 4,
+            modes: 4,
+            motd_path: "motd.txt".to_string(),
+            network: "Platform".to_string(),
+            nick_delay: Duration::from_secs(10),
+            nick_length: NICK_LENGTH,
+            operators: HashMap::new(),
+            ping_frequency: Duration::from_secs(120),
+            ping_timeout: Duration::from_secs(60),
+            prefix_modes: "qaohv".to_string(),
+            registration_timeout: Duration::from_secs(60),
+            sendq: 1048576,
+            topic_length: TOPIC_LENGTH,
+            user_length: USER_LENGTH,
+            whowas_length: 1000,
+        }
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+// Returns true when text matches mask, where * matches any run of characters
+// and ? matches any single character. Callers fold both with the casemapping
+// first so the match ignores case.
+pub fn matches(mask: &str, text: &str) -> bool {
+    let mask: Vec<char> = mask.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut m, mut t) = (0, 0);
+    // Where the last * was seen and how much text it had consumed.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if m < mask.len() && (mask[m] == '?' || mask[m] == text[t]) {
+            m += 1;
+            t += 1;
+        } else if m < mask.len() && mask[m] == '*' {
+            star = Some((m, t));
+            m += 1;
+        } else if let Some((star_m, star_t)) = star {
+            // Let the last * swallow one more character and try again.
+            m = star_m + 1;
+            t = star_t + 1;
+            star = Some((star_m, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    mask[m..].iter().all(|c| *c == '*')
+}
+
+// Expand a partial mask to the full nick!user@host form, so a bare nick or
+// user@host still matches whole prefixes.
+pub fn normalize(mask: &str) -> String {
+    let (nick, rest) = match mask.find('!') {
+        Some(i) => (&mask[..i], &mask[i + 1..]),
+        None if mask.contains('@') => ("*", mask),
+        None => (mask, "*"),
+    };
+    let (user, host) = match rest.find('@') {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, "*"),
+    };
+    let part = |part: &str| {
+        if part.is_empty() {
+            "*".to_string()
+        } else {
+            part.to_string()
+        }
+    };
+    format!("{:}!{:}@{:}", part(nick), part(user), part(host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::casemapping::Casemapping;
+
+    #[test]
+    fn stars() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "nick!user@host"));
+        assert!(matches("nick!*@*", "nick!user@host"));
+        assert!(matches("*!*@host", "nick!user@host"));
+        assert!(matches("n*k!u*r@*.example", "nick!user@irc.example"));
+        assert!(matches("*a*a*", "banana"));
+        assert!(!matches("nick!*@*", "nicky!user@host"));
+        assert!(!matches("*!*@host", "nick!user@host.example"));
+        assert!(!matches("*a*z*", "banana"));
+    }
+
+    #[test]
+    fn question_marks() {
+        assert!(matches("n?ck", "nick"));
+        assert!(matches("????", "nick"));
+        assert!(matches("?*", "n"));
+        assert!(!matches("????", "nic"));
+        assert!(!matches("????", "nicks"));
+        assert!(!matches("?", ""));
+        assert!(!matches("?*", ""));
+    }
+
+    #[test]
+    fn star_runs() {
+        assert!(matches("**", ""));
+        assert!(matches("**", "nick"));
+        assert!(matches("n**k", "nick"));
+        assert!(matches("n**k", "nk"));
+        assert!(matches("***!***@***", "nick!user@host"));
+        assert!(matches("*?*", "n"));
+        assert!(!matches("n**k", "nickname"));
+        assert!(!matches("*?*?*", "n"));
+    }
+
+    #[test]
+    fn normalized_masks() {
+        assert_eq!(normalize("nick"), "nick!*@*");
+        assert_eq!(normalize("nick!user"), "nick!user@*");
+        assert_eq!(normalize("user@host"), "*!user@host");
+        assert_eq!(normalize("@host"), "*!*@host");
+        assert_eq!(normalize("nick!@host"), "nick!*@host");
+        assert_eq!(normalize("!"), "*!*@*");
+        assert_eq!(normalize(""), "*!*@*");
+        assert_eq!(normalize("nick!user@host"), "nick!user@host");
+    }
+
+    #[test]
+    fn casemapped() {
+        let matches_folded = |casemapping: Casemapping, mask: &str, text: &str| {
+            matches(&casemapping.fold(mask), &casemapping.fold(text))
+        };
+        let mask = normalize("NICK[A]");
+        assert!(matches_folded(
+            Casemapping::Rfc1459,
+            &mask,
+            "nick{a}!User@Host"
+        ));
+        assert!(matches_folded(
+            Casemapping::StrictRfc1459,
+            &mask,
+            "nick{a}!User@Host"
+        ));
+        assert!(!matches_folded(
+            Casemapping::Ascii,
+            &mask,
+            "nick{a}!User@Host"
+        ));
+        assert!(matches_folded(
+            Casemapping::Ascii,
+            &mask,
+            "nick[a]!User@Host"
+        ));
+        assert!(matches_folded(
+            Casemapping::Rfc1459,
+            "*!*@HOST~",
+            "n!u@host^"
+        ));
+        assert!(!matches_folded(
+            Casemapping::StrictRfc1459,
+            "*!*@HOST~",
+            "n!u@host^"
+        ));
+        assert!(!matches(&normalize("NICK"), "nick!user@host"));
+    }
+}
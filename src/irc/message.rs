@@ -15,45 +15,128 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with Platform.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::irc::BUFFER_SIZE;
+use crate::irc::{BUFFER_SIZE, MAX_PARAMETERS, TAG_BUFFER_SIZE};
+use mio::net::TcpStream;
+use std::collections::BTreeMap;
 use std::io::ErrorKind;
-use std::net::TcpStream;
 use std::net::{IpAddr, Ipv4Addr};
 use std::ops::Add;
-use std::str::from_utf8;
+use std::sync::Arc;
 
+// Buffer accumulates bytes read from a connection until they form complete
+// CRLF (or bare LF) terminated lines.
+pub struct Buffer {
+    data: Vec<u8>,
+    discarding: bool,
+}
+
+impl Buffer {
+    pub fn extend(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    // Remove every complete line from the buffer and return them as a Request.
+    // Lines longer than the limit are dropped and counted as overflow instead.
+    pub fn request(&mut self) -> Option<Request> {
+        let mut request = Request::new();
+
+        let mut start = 0;
+        while let Some(i) = self.data[start..].iter().position(|b| *b == b'\n') {
+            let end = start + i + 1;
+            let line = &self.data[start..end];
+            start = end;
+
+            // The start of this line was already reported as too long.
+            if self.discarding {
+                self.discarding = false;
+                continue;
+            }
+
+            if too_long(line) {
+                request.add_overflow();
+            } else {
+                request.data().extend_from_slice(line);
+            }
+        }
+        self.data.drain(..start);
+
+        // Stop buffering a partial line once it can no longer fit.
+        if too_long(&self.data) {
+            if !self.discarding {
+                request.add_overflow();
+                self.discarding = true;
+            }
+            self.data.clear();
+        }
+
+        if request.size() == 0 && request.overflow() == 0 {
+            None
+        } else {
+            Some(request)
+        }
+    }
+
+    pub fn new() -> Buffer {
+        Buffer {
+            data: Vec::new(),
+            discarding: false,
+        }
+    }
+}
+
+// A line may be BUFFER_SIZE bytes including its terminator, plus up to
+// TAG_BUFFER_SIZE bytes of message tags including the leading @ and the
+// trailing space.
+fn too_long(line: &[u8]) -> bool {
+    let tags = if line.first() == Some(&b'@') {
+        match line.iter().position(|b| *b == b' ') {
+            Some(i) => i + 1,
+            None => line.len(),
+        }
+    } else {
+        0
+    };
+    tags > TAG_BUFFER_SIZE || line.len() - tags > BUFFER_SIZE
+}
+
+// Connection is a cheap handle to a client's stream that can be shared between
+// the Listener, Workers and the client's Session.
+#[derive(Clone)]
 pub struct Connection {
-    tcp_stream: TcpStream,
+    id: u64,
+    tcp_stream: Arc<TcpStream>,
 }
 
 impl Connection {
-    pub fn id(&self) -> String {
-        let ip = match self.tcp_stream.peer_addr() {
-            Ok(addr) => addr.ip(),
-            Err(_e) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-        };
-        let port = match self.tcp_stream.peer_addr() {
-            Ok(addr) => addr.port(),
-            Err(_e) => 0,
-        };
-        format!("{:}:{:}", ip, port)
+    pub fn host(&self) -> String {
+        match self.tcp_stream.peer_addr() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(_e) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)).to_string(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
     }
 
     pub fn stream(&self) -> &TcpStream {
         &self.tcp_stream
     }
 
-    pub fn new(tcp_stream: TcpStream) -> Connection {
+    pub fn new(id: u64, tcp_stream: TcpStream) -> Connection {
         Connection {
-            tcp_stream: tcp_stream,
+            id,
+            tcp_stream: Arc::new(tcp_stream),
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct Message {
     command: String,
     parameters: Vec<String>,
     prefix: String,
+    tags: BTreeMap<String, String>,
 }
 
 impl Message {
@@ -61,6 +144,16 @@ impl Message {
         self.parameters.push(parameter.to_string());
     }
 
+    // Returns only the client-only tags, those prefixed with +, which are
+    // relayed between clients untouched.
+    pub fn client_tags(&self) -> BTreeMap<String, String> {
+        self.tags
+            .iter()
+            .filter(|(key, _value)| key.starts_with('+'))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
     pub fn command(&self) -> &String {
         &self.command
     }
@@ -69,6 +162,10 @@ impl Message {
         &self.parameters
     }
 
+    pub fn remove_tag(&mut self, key: &str) {
+        self.tags.remove(key);
+    }
+
     pub fn set_command(&mut self, command: &str) {
         self.command = command.to_string();
     }
@@ -77,67 +174,163 @@ impl Message {
         self.prefix = prefix.to_string();
     }
 
-    pub fn string(&self) -> String {
-        let mut string = String::new();
+    // Set a tag. An empty value is equivalent to a tag without a value.
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        self.tags.insert(key.to_string(), value.to_string());
+    }
+
+    // Serialize the message as a CRLF terminated line. The last parameter is
+    // sent as a trailing parameter whenever it needs to be. Messages that cannot
+    // be represented on the wire, such as a middle parameter containing a space,
+    // are rejected.
+    pub fn string(&self) -> Result<String, ErrorKind> {
+        let mut string = self.tags_string();
         if !self.prefix.is_empty() {
-            string.push_str(":");
+            if self.prefix.contains(|c| c == ' ' || forbidden(c)) {
+                return Err(ErrorKind::InvalidData);
+            }
+            string.push(':');
             string.push_str(&self.prefix);
-            string.push_str(" ");
+            string.push(' ');
+        }
+
+        let letters = self.command.chars().all(|c| c.is_ascii_alphabetic());
+        let numeric = self.command.len() == 3 && self.command.chars().all(|c| c.is_ascii_digit());
+        if self.command.is_empty() || !(letters || numeric) {
+            return Err(ErrorKind::InvalidData);
         }
         string.push_str(&self.command);
-        for p in &self.parameters {
-            string.push_str(" ");
-            if p.contains(" ") {
-                string.push_str(":");
-                string.push_str(&p);
-                break;
+
+        if self.parameters.len() > MAX_PARAMETERS {
+            return Err(ErrorKind::InvalidData);
+        }
+        for (i, p) in self.parameters.iter().enumerate() {
+            if p.contains(forbidden) {
+                return Err(ErrorKind::InvalidData);
             }
-            string.push_str(&p);
+            string.push(' ');
+            if !valid_middle(p) {
+                if i + 1 < self.parameters.len() {
+                    return Err(ErrorKind::InvalidData);
+                }
+                string.push(':');
+            }
+            string.push_str(p);
         }
         string.push_str("\r\n");
-        string
+        Ok(string)
+    }
+
+    pub fn tag(&self, key: &str) -> Option<&String> {
+        self.tags.get(key)
+    }
+
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    // Returns the serialized tag section including the leading @ and trailing
+    // space, or an empty string when there are no tags.
+    pub fn tags_string(&self) -> String {
+        if self.tags.is_empty() {
+            return String::new();
+        }
+        let tags: Vec<String> = self
+            .tags
+            .iter()
+            .map(|(key, value)| {
+                if value.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{:}={:}", key, escape_tag_value(value))
+                }
+            })
+            .collect();
+        format!("@{:} ", tags.join(";"))
+    }
+
+    pub fn from_parts(prefix: &str, command: &str, parameters: &[&str]) -> Message {
+        Message {
+            command: command.to_string(),
+            parameters: parameters.iter().map(|p| p.to_string()).collect(),
+            prefix: prefix.to_string(),
+            tags: BTreeMap::new(),
+        }
     }
 
-    pub fn from_string(string: String) -> Message {
+    // Parse a single line, without its CRLF, following RFC 1459 and the IRCv3
+    // message-tags specification:
+    //
+    // [@tags SPACE] [:prefix SPACE] command *(SPACE middle) [SPACE :trailing]
+    //
+    // Any number of spaces may separate tokens. After 14 middle parameters the
+    // rest of the line is taken as the 15th parameter.
+    pub fn from_string(string: String) -> Result<Message, ParseError> {
+        let mut tags = BTreeMap::new();
         let mut prefix = String::new();
-        let mut command = String::new();
         let mut parameters = Vec::new();
-        let mut last_parameter = String::new();
-
-        for (i, p) in string.split(' ').enumerate() {
-            if i == 0 {
-                if p.chars().nth(0) == Some(':') {
-                    let mut p = p.to_string();
-                    p.remove(0);
-                    prefix = p;
-                } else {
-                    command = p.to_string();
+        let mut rest = string.trim_start_matches(' ');
+
+        // Split off the tag section if there is one.
+        if let Some(tagged) = rest.strip_prefix('@') {
+            let (section, remainder) = token(tagged);
+            if section.is_empty() {
+                return Err(ParseError::InvalidTags);
+            }
+            for tag in section.split(';') {
+                let (key, value) = match tag.find('=') {
+                    Some(i) => (&tag[..i], unescape_tag_value(&tag[i + 1..])),
+                    None => (tag, String::new()),
+                };
+                if key.is_empty() {
+                    return Err(ParseError::InvalidTags);
                 }
-            } else if i == 1 && !prefix.is_empty() {
-                command = p.to_string();
-            } else if !last_parameter.is_empty() {
-                last_parameter.push_str(p);
-                last_parameter.push_str(" ");
-            } else if p.chars().nth(0) == Some(':') {
-                let mut p = p.to_string();
-                p.remove(0);
-                last_parameter.push_str(&p);
-                last_parameter.push_str(" ");
-            } else {
-                parameters.push(p.to_string());
+                tags.insert(key.to_string(), value);
             }
+            rest = remainder;
         }
 
-        if !last_parameter.is_empty() {
-            last_parameter.pop();
-            parameters.push(last_parameter);
+        // Split off the prefix if there is one.
+        if let Some(prefixed) = rest.strip_prefix(':') {
+            let (source, remainder) = token(prefixed);
+            if source.is_empty() {
+                return Err(ParseError::InvalidPrefix);
+            }
+            prefix = source.to_string();
+            rest = remainder;
         }
 
-        Message {
-            command: command,
-            parameters: parameters,
-            prefix: prefix,
+        // The command is either letters or a three digit numeric.
+        let (command, mut rest) = token(rest);
+        if command.is_empty() {
+            return Err(ParseError::MissingCommand);
+        }
+        let letters = command.chars().all(|c| c.is_ascii_alphabetic());
+        let numeric = command.len() == 3 && command.chars().all(|c| c.is_ascii_digit());
+        if !letters && !numeric {
+            return Err(ParseError::InvalidCommand(command.to_string()));
+        }
+
+        while !rest.is_empty() {
+            if let Some(trailing) = rest.strip_prefix(':') {
+                parameters.push(trailing.to_string());
+                break;
+            }
+            if parameters.len() == MAX_PARAMETERS - 1 {
+                parameters.push(rest.to_string());
+                break;
+            }
+            let (middle, remainder) = token(rest);
+            parameters.push(middle.to_string());
+            rest = remainder;
         }
+
+        Ok(Message {
+            command: command.to_string(),
+            parameters,
+            prefix,
+            tags,
+        })
     }
 
     pub fn new() -> Message {
@@ -145,8 +338,75 @@ impl Message {
             command: String::new(),
             parameters: Vec::new(),
             prefix: String::new(),
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    InvalidCommand(String),
+    InvalidPrefix,
+    InvalidTags,
+    MissingCommand,
+}
+
+// Escape a tag value so it can be sent on the wire.
+fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
         }
     }
+    escaped
+}
+
+// NUL, CR and LF can never appear inside a message.
+fn forbidden(c: char) -> bool {
+    c == '\0' || c == '\r' || c == '\n'
+}
+
+// Returns true if a parameter can be sent as a middle parameter, rather than
+// only as the trailing one.
+pub fn valid_middle(parameter: &str) -> bool {
+    !parameter.is_empty() && !parameter.starts_with(':') && !parameter.contains(' ')
+}
+
+// Split the next space delimited token off the front of a string, skipping
+// any extra spaces before the remainder.
+fn token(string: &str) -> (&str, &str) {
+    match string.find(' ') {
+        Some(i) => (&string[..i], string[i..].trim_start_matches(' ')),
+        None => (string, ""),
+    }
+}
+
+// Reverse escape_tag_value. An unknown escape drops the backslash and a
+// trailing lone backslash is removed.
+fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
 }
 
 pub struct Reply {
@@ -162,29 +422,49 @@ impl Reply {
         self.messages.append(messages);
     }
 
+    pub fn messages(&self) -> &Vec<Message> {
+        &self.messages
+    }
+
     pub fn mut_messages(&mut self) -> &mut Vec<Message> {
         &mut self.messages
     }
 
-    pub fn strings(&self) -> Result<Vec<String>, ErrorKind> {
+    // Serialize the messages, packing as many lines into each string as fit
+    // within BUFFER_SIZE. A message that cannot be sent is dropped on its own
+    // without affecting the rest.
+    pub fn strings(&self) -> Vec<String> {
         let mut data = Vec::new();
         let mut buffer = String::new();
         for message in &self.messages {
-            let string = message.string();
-            if string.as_bytes().len() > BUFFER_SIZE {
-                return Err(ErrorKind::InvalidData);
+            let string = match message.string() {
+                Ok(string) => string,
+                Err(_e) => continue,
+            };
+
+            // Tags have their own budget on top of BUFFER_SIZE.
+            let tags = message.tags_string().len();
+            if tags > TAG_BUFFER_SIZE || string.len() - tags > BUFFER_SIZE {
+                eprintln!(
+                    "Dropped oversized {:} message of {:} bytes",
+                    message.command(),
+                    string.len()
+                );
+                continue;
             }
-            if buffer.as_bytes().len() + string.as_bytes().len() <= BUFFER_SIZE {
+            if buffer.len() + string.len() <= BUFFER_SIZE {
                 buffer.push_str(&string);
             } else {
-                data.push(buffer);
-                buffer = message.string();
+                if !buffer.is_empty() {
+                    data.push(buffer);
+                }
+                buffer = string;
             }
         }
         if !buffer.is_empty() {
             data.push(buffer);
         }
-        Ok(data)
+        data
     }
 
     pub fn new() -> Reply {
@@ -205,73 +485,483 @@ impl Add for Reply {
 }
 
 pub struct Request {
-    data: [u8; BUFFER_SIZE],
-    messages: Vec<Message>,
-    size: usize,
+    data: Vec<u8>,
+    disconnect: Option<String>,
+    messages: Vec<Result<Message, ParseError>>,
+    overflow: usize,
 }
 
 impl Request {
+    pub fn add_overflow(&mut self) {
+        self.overflow += 1;
+    }
+
     pub fn clear_data(&mut self) {
-        self.data = [0 as u8; BUFFER_SIZE];
+        self.data.clear();
+        self.disconnect = None;
         self.messages.clear();
-        self.size = 0;
+        self.overflow = 0;
     }
 
-    pub fn data(&mut self) -> &mut [u8; BUFFER_SIZE] {
+    pub fn data(&mut self) -> &mut Vec<u8> {
         &mut self.data
     }
 
-    pub fn messages(&mut self) -> &Vec<Message> {
+    // Returns why the connection went away when this request only reports
+    // that the client has disconnected.
+    pub fn disconnect(&self) -> Option<&String> {
+        self.disconnect.as_ref()
+    }
+
+    pub fn messages(&mut self) -> &Vec<Result<Message, ParseError>> {
         if self.messages.is_empty() {
-            for message in self.string().split("\r\n") {
-                if message != "" {
-                    self.messages
-                        .push(Message::from_string(message.to_string()));
+            for line in self.data.split(|b| *b == b'\n') {
+                let line = match line.last() {
+                    Some(b'\r') => &line[..line.len() - 1],
+                    _ => line,
+                };
+                if !line.is_empty() {
+                    let string = String::from_utf8_lossy(line).to_string();
+                    self.messages.push(Message::from_string(string));
                 }
             }
         }
         &self.messages
     }
 
-    pub fn size(&mut self) -> usize {
-        let mut size: usize = 0;
+    // Returns the number of lines dropped for exceeding the line length limit.
+    pub fn overflow(&self) -> usize {
+        self.overflow
+    }
+
+    pub fn set_disconnect(&mut self, reason: &str) {
+        self.disconnect = Some(reason.to_string());
+    }
 
-        if self.size == 0 {
-            for c in &self.data[..] {
-                if *c == 0 {
-                    break;
-                }
-                size = size + 1;
-            }
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn string(&self) -> String {
+        String::from_utf8_lossy(&self.data).to_string()
+    }
+
+    // A request is valid when it only holds complete lines.
+    pub fn valid(&self) -> bool {
+        self.data.last() == Some(&b'\n')
+    }
 
-            self.size = size;
+    pub fn new() -> Request {
+        Request {
+            data: Vec::new(),
+            disconnect: None,
+            messages: Vec::new(),
+            overflow: 0,
         }
+    }
+}
 
-        self.size
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parse a line and return its prefix, command and parameters.
+    fn split(line: &str) -> (String, String, Vec<String>) {
+        let message = Message::from_string(line.to_string()).unwrap();
+        (
+            message.prefix.clone(),
+            message.command().clone(),
+            message.parameters().clone(),
+        )
     }
 
-    pub fn string(&mut self) -> String {
-        let size = self.size();
-        match from_utf8(&self.data()[..size]) {
-            Ok(s) => s.to_string(),
-            Err(_e) => "".to_string(),
+    fn parts(prefix: &str, command: &str, parameters: &[&str]) -> (String, String, Vec<String>) {
+        (
+            prefix.to_string(),
+            command.to_string(),
+            parameters.iter().map(|p| p.to_string()).collect(),
+        )
+    }
+
+    // Cases from the ircdocs parser-tests msg-split corpus.
+    #[test]
+    fn msg_split() {
+        let cases: &[(&str, &str, &str, &[&str])] = &[
+            ("foo bar baz asdf", "", "foo", &["bar", "baz", "asdf"]),
+            (
+                ":coolguy foo bar baz asdf",
+                "coolguy",
+                "foo",
+                &["bar", "baz", "asdf"],
+            ),
+            (
+                "foo bar baz :asdf quux",
+                "",
+                "foo",
+                &["bar", "baz", "asdf quux"],
+            ),
+            ("foo bar baz :", "", "foo", &["bar", "baz", ""]),
+            ("foo bar baz ::asdf", "", "foo", &["bar", "baz", ":asdf"]),
+            (
+                ":coolguy foo bar baz :asdf quux",
+                "coolguy",
+                "foo",
+                &["bar", "baz", "asdf quux"],
+            ),
+            (
+                ":coolguy foo bar baz :  asdf quux ",
+                "coolguy",
+                "foo",
+                &["bar", "baz", "  asdf quux "],
+            ),
+            (
+                ":coolguy PRIVMSG bar :lol :) ",
+                "coolguy",
+                "PRIVMSG",
+                &["bar", "lol :) "],
+            ),
+            (
+                ":coolguy foo bar baz :",
+                "coolguy",
+                "foo",
+                &["bar", "baz", ""],
+            ),
+            (
+                ":coolguy foo bar baz :  ",
+                "coolguy",
+                "foo",
+                &["bar", "baz", "  "],
+            ),
+            (":src JOIN #chan", "src", "JOIN", &["#chan"]),
+            (":src JOIN :#chan", "src", "JOIN", &["#chan"]),
+            (":src AWAY", "src", "AWAY", &[]),
+            (":src AWAY ", "src", "AWAY", &[]),
+            (
+                ":cool\tguy foo bar baz",
+                "cool\tguy",
+                "foo",
+                &["bar", "baz"],
+            ),
+            (
+                ":coolguy!ag@net\x035w\x03ork.admin PRIVMSG foo :bar baz",
+                "coolguy!ag@net\x035w\x03ork.admin",
+                "PRIVMSG",
+                &["foo", "bar baz"],
+            ),
+            (
+                ":irc.example.com COMMAND param1 param2 :param3 param3",
+                "irc.example.com",
+                "COMMAND",
+                &["param1", "param2", "param3 param3"],
+            ),
+            ("COMMAND", "", "COMMAND", &[]),
+            ("001 nick :Welcome", "", "001", &["nick", "Welcome"]),
+        ];
+        for (line, prefix, command, parameters) in cases {
+            assert_eq!(
+                split(line),
+                parts(prefix, command, parameters),
+                "{:?}",
+                line
+            );
         }
     }
 
-    pub fn valid(&mut self) -> bool {
-        let size = self.size();
-        if size > 2 && self.data[size - 1] == b'\n' && self.data[size - 2] == b'\r' {
-            true
-        } else {
-            false
+    #[test]
+    fn repeated_spaces() {
+        assert_eq!(
+            split("  :src   foo   bar  baz   :quux  "),
+            parts("src", "foo", &["bar", "baz", "quux  "])
+        );
+        assert_eq!(split("foo   bar   "), parts("", "foo", &["bar"]));
+    }
+
+    #[test]
+    fn parameter_limit() {
+        // After 14 middle parameters the rest of the line is the 15th.
+        let line = "FOO 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 :17";
+        let (_prefix, _command, parameters) = split(line);
+        assert_eq!(parameters.len(), MAX_PARAMETERS);
+        assert_eq!(parameters[13], "14");
+        assert_eq!(parameters[14], "15 16 :17");
+
+        let line = "FOO 1 2 3 4 5 6 7 8 9 10 11 12 13 14 :15 16";
+        let (_prefix, _command, parameters) = split(line);
+        assert_eq!(parameters.len(), MAX_PARAMETERS);
+        assert_eq!(parameters[14], "15 16");
+    }
+
+    #[test]
+    fn tags() {
+        let message = Message::from_string("@a=b;c=32;k;rt=ql7 foo".to_string()).unwrap();
+        assert_eq!(message.tag("a").unwrap(), "b");
+        assert_eq!(message.tag("c").unwrap(), "32");
+        assert_eq!(message.tag("k").unwrap(), "");
+        assert_eq!(message.tag("rt").unwrap(), "ql7");
+        assert_eq!(message.command(), "foo");
+
+        let line = "@a=b\\\\and\\nk;c=72\\s45;d=gh\\:764 foo";
+        let message = Message::from_string(line.to_string()).unwrap();
+        assert_eq!(message.tag("a").unwrap(), "b\\and\nk");
+        assert_eq!(message.tag("c").unwrap(), "72 45");
+        assert_eq!(message.tag("d").unwrap(), "gh;764");
+
+        let message = Message::from_string("@c;h=;a=b :quux ab cd".to_string()).unwrap();
+        assert_eq!(message.tag("c").unwrap(), "");
+        assert_eq!(message.tag("h").unwrap(), "");
+        assert_eq!(message.tag("a").unwrap(), "b");
+        assert_eq!(split("@c;h=;a=b :quux ab cd"), parts("quux", "ab", &["cd"]));
+
+        // Unknown escapes drop the backslash and a lone trailing one is removed.
+        let message = Message::from_string("@foo=\\b;bar=x\\ COMMAND".to_string()).unwrap();
+        assert_eq!(message.tag("foo").unwrap(), "b");
+        assert_eq!(message.tag("bar").unwrap(), "x");
+    }
+
+    #[test]
+    fn invalid_commands() {
+        for command in &["FOO1 bar", "1234 bar", "12 bar", "F-O bar", ":src 0x1 bar"] {
+            match Message::from_string(command.to_string()) {
+                Err(ParseError::InvalidCommand(_command)) => {}
+                result => panic!("{:?} parsed as {:?}", command, result),
+            }
         }
     }
 
-    pub fn new() -> Request {
-        Request {
-            data: [0 as u8; BUFFER_SIZE],
-            messages: Vec::new(),
-            size: 0,
+    #[test]
+    fn incomplete_lines() {
+        let cases = [
+            ("", ParseError::MissingCommand),
+            ("   ", ParseError::MissingCommand),
+            ("@a=b", ParseError::MissingCommand),
+            ("@a=b ", ParseError::MissingCommand),
+            (":prefix", ParseError::MissingCommand),
+            (":prefix ", ParseError::MissingCommand),
+            ("@a=b :prefix", ParseError::MissingCommand),
+            ("@ foo", ParseError::InvalidTags),
+            ("@=b foo", ParseError::InvalidTags),
+            (": foo", ParseError::InvalidPrefix),
+        ];
+        for (line, error) in cases.iter() {
+            assert_eq!(
+                Message::from_string(line.to_string()).as_ref().err(),
+                Some(error),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    // Serialize a message and parse it back without its CRLF.
+    fn round_trip(message: &Message) -> Message {
+        let string = message.string().unwrap();
+        assert!(string.ends_with("\r\n"));
+        Message::from_string(string.trim_end_matches("\r\n").to_string()).unwrap()
+    }
+
+    #[test]
+    fn trailing_parameters() {
+        let message = Message::from_parts("nick!user@host", "PRIVMSG", &["#chan", ""]);
+        assert_eq!(
+            message.string().unwrap(),
+            ":nick!user@host PRIVMSG #chan :\r\n"
+        );
+        assert_eq!(round_trip(&message), message);
+
+        let message = Message::from_parts("", "PRIVMSG", &["#chan", ":)"]);
+        assert_eq!(message.string().unwrap(), "PRIVMSG #chan ::)\r\n");
+        assert_eq!(round_trip(&message), message);
+
+        let message = Message::from_parts("", "PRIVMSG", &["#chan", "hello world"]);
+        assert_eq!(message.string().unwrap(), "PRIVMSG #chan :hello world\r\n");
+        assert_eq!(round_trip(&message), message);
+
+        let message = Message::from_parts("", "JOIN", &["#chan"]);
+        assert_eq!(message.string().unwrap(), "JOIN #chan\r\n");
+        assert_eq!(round_trip(&message), message);
+    }
+
+    #[test]
+    fn rejected_messages() {
+        let middles: &[&[&str]] = &[
+            &["a b", "c"],
+            &["", "c"],
+            &[":a", "c"],
+            &["a\rb", "c"],
+            &["a\nb", "c"],
+            &["#chan", "a\r\nQUIT"],
+            &["#chan", "a\0b"],
+        ];
+        for parameters in middles {
+            let message = Message::from_parts("", "PRIVMSG", parameters);
+            assert!(message.string().is_err(), "{:?}", parameters);
         }
+        assert!(Message::from_parts("a b", "PRIVMSG", &[]).string().is_err());
+        assert!(Message::from_parts("", "PRIV MSG", &[]).string().is_err());
+        assert!(Message::from_parts("", "12", &[]).string().is_err());
+        let sixteen = ["x"; MAX_PARAMETERS + 1];
+        assert!(Message::from_parts("", "FOO", &sixteen).string().is_err());
+    }
+
+    #[test]
+    fn tag_round_trip() {
+        let mut message = Message::from_parts("src", "TAGMSG", &["#chan"]);
+        message.set_tag("+example", "a;b c\\d\r\ne");
+        message.set_tag("time", "2020-01-01T00:00:00.000Z");
+        message.set_tag("flag", "");
+        assert_eq!(round_trip(&message), message);
+    }
+
+    // A small xorshift generator keeps the generated cases reproducible.
+    fn next(state: &mut u64, n: usize) -> usize {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        (*state % n as u64) as usize
+    }
+
+    fn word(state: &mut u64, max: usize) -> String {
+        let alphabet = [
+            'a', 'Z', '#', ':', ' ', '!', '@', '\\', ';', '=', '\u{e9}', '\r',
+        ];
+        let length = next(state, max + 1);
+        (0..length)
+            .map(|_| alphabet[next(state, alphabet.len())])
+            .collect()
+    }
+
+    // Every message that serializes successfully parses back to itself.
+    #[test]
+    fn round_trip_property() {
+        let commands = ["PRIVMSG", "NOTICE", "001", "JOIN", "mode"];
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+
+        let mut serialized = 0;
+        for _ in 0..5000 {
+            let prefix = word(&mut state, 6);
+            let count = next(&mut state, MAX_PARAMETERS + 1);
+            let parameters: Vec<String> = (0..count).map(|_| word(&mut state, 5)).collect();
+            let parameters: Vec<&str> = parameters.iter().map(|p| p.as_str()).collect();
+            let command = commands[next(&mut state, commands.len())];
+            let mut message = Message::from_parts(&prefix, command, &parameters);
+            if next(&mut state, 2) == 0 {
+                let key = format!("+k{:}", next(&mut state, 10));
+                message.set_tag(&key, &word(&mut state, 6));
+            }
+
+            if let Ok(string) = message.string() {
+                serialized += 1;
+                let parsed = Message::from_string(string.trim_end_matches("\r\n").to_string());
+                assert_eq!(parsed.as_ref(), Ok(&message), "{:?}", string);
+            }
+        }
+        assert!(serialized > 100);
+    }
+
+    // Feed each read to a Buffer and collect the requests it gives back.
+    fn requests(reads: &[&[u8]]) -> Vec<Option<(String, usize)>> {
+        let mut buffer = Buffer::new();
+        reads
+            .iter()
+            .map(|data| {
+                buffer.extend(data);
+                buffer
+                    .request()
+                    .map(|request| (request.string(), request.overflow()))
+            })
+            .collect()
+    }
+
+    // A line of length bytes, including its CRLF.
+    fn line(prefix: &str, length: usize) -> String {
+        let mut line = prefix.to_string();
+        line.push_str(&"x".repeat(length - prefix.len() - 2));
+        line.push_str("\r\n");
+        line
+    }
+
+    #[test]
+    fn split_reads() {
+        let reads: &[&[u8]] = &[b"PRIVMSG #a :hel", b"lo\r", b"\nNICK", b" b\r\n"];
+        assert_eq!(
+            requests(reads),
+            vec![
+                None,
+                None,
+                Some(("PRIVMSG #a :hello\r\n".to_string(), 0)),
+                Some(("NICK b\r\n".to_string(), 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn bare_line_feeds() {
+        let mut buffer = Buffer::new();
+        buffer.extend(b"NICK a\nUSER a 0 * :A\nPING");
+        let mut request = buffer.request().unwrap();
+        assert!(request.valid());
+        let commands: Vec<&String> = request
+            .messages()
+            .iter()
+            .map(|message| message.as_ref().unwrap().command())
+            .collect();
+        assert_eq!(commands, vec!["NICK", "USER"]);
+
+        buffer.extend(b" x\n");
+        assert_eq!(buffer.request().unwrap().string(), "PING x\n");
+    }
+
+    #[test]
+    fn long_lines() {
+        let longest = line("PRIVMSG #a :", BUFFER_SIZE);
+        let long = line("PRIVMSG #a :", BUFFER_SIZE + 1);
+        assert_eq!(
+            requests(&[longest.as_bytes()]),
+            vec![Some((longest.clone(), 0))]
+        );
+        assert_eq!(
+            requests(&[long.as_bytes(), b"NICK a\r\n"]),
+            vec![
+                Some((String::new(), 1)),
+                Some(("NICK a\r\n".to_string(), 0))
+            ]
+        );
+
+        // A long line arriving over several reads is only reported once and
+        // is discarded up to the next LF.
+        let start = "x".repeat(BUFFER_SIZE + 1);
+        let reads: &[&[u8]] = &[
+            start.as_bytes(),
+            start.as_bytes(),
+            b"xxx\r\nNICK a\r",
+            b"\n",
+        ];
+        assert_eq!(
+            requests(reads),
+            vec![
+                Some((String::new(), 1)),
+                None,
+                None,
+                Some(("NICK a\r\n".to_string(), 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn tagged_lines() {
+        // Tags may add TAG_BUFFER_SIZE bytes, counting the @ and the space.
+        let tags = format!("@a={:} ", "t".repeat(TAG_BUFFER_SIZE - 4));
+        let longest = line(&tags, tags.len() + BUFFER_SIZE);
+        assert_eq!(
+            requests(&[longest.as_bytes()]),
+            vec![Some((longest.clone(), 0))]
+        );
+
+        let long = line(&tags, tags.len() + BUFFER_SIZE + 1);
+        assert_eq!(requests(&[long.as_bytes()]), vec![Some((String::new(), 1))]);
+
+        let tags = format!("@a={:} ", "t".repeat(TAG_BUFFER_SIZE - 3));
+        let long = line(&tags, tags.len() + 20);
+        assert_eq!(requests(&[long.as_bytes()]), vec![Some((String::new(), 1))]);
     }
 }
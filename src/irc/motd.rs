@@ -0,0 +1,65 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs::{metadata, read};
+use std::time::SystemTime;
+
+// Motd caches the message of the day read from a file. The file is read again
+// whenever its modification time changes, so it can be edited while the
+// server is running.
+pub struct Motd {
+    lines: Option<Vec<String>>,
+    modified: Option<SystemTime>,
+    path: String,
+}
+
+impl Motd {
+    // Returns the lines of the message of the day, or None when the file is
+    // missing or cannot be read.
+    pub fn lines(&mut self) -> Option<&Vec<String>> {
+        let modified = match metadata(&self.path) {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(_e) => {
+                self.lines = None;
+                self.modified = None;
+                return None;
+            }
+        };
+
+        if self.lines.is_none() || modified.is_none() || modified != self.modified {
+            self.lines = match read(&self.path) {
+                Ok(data) => Some(
+                    String::from_utf8_lossy(&data)
+                        .lines()
+                        .map(|line| line.to_string())
+                        .collect(),
+                ),
+                Err(_e) => None,
+            };
+            self.modified = modified;
+        }
+        self.lines.as_ref()
+    }
+
+    pub fn new(path: &str) -> Motd {
+        Motd {
+            lines: None,
+            modified: None,
+            path: path.to_string(),
+        }
+    }
+}
@@ -15,86 +15,662 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with Platform.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::irc::message::{Connection, Message, Reply, Request};
-use std::io::Write;
-use std::net::Shutdown;
-use std::sync::Arc;
+use crate::irc::capability::Capabilities;
+use crate::irc::channel::{Channels, FLAG_MODES, KEY_MODES, LIMIT_MODES, LIST_MODES};
+use crate::irc::config::Config;
+use crate::irc::message::{valid_middle, Connection, Message, ParseError, Reply, Request};
+use crate::irc::motd::Motd;
+use crate::irc::service::list::Listings;
+use crate::irc::session::{Session, SharedSessions};
+use crate::irc::whowas::{Whowas, WhowasEntry};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-pub struct Service {}
+mod away;
+mod cap;
+mod channel;
+mod info;
+mod invite;
+mod isupport;
+mod list;
+mod message;
+mod mode;
+mod oper;
+mod ping;
+mod quit;
+mod who;
+mod whois;
+
+const USER_MODES: &str = "BRiosw";
+const VERSION: &str = concat!("platform-", env!("CARGO_PKG_VERSION"));
+
+// Lock ordering: Sessions before Channels before pending LISTs before any
+// individual Session, with Whowas, the MOTD and the user count last.
+pub struct Service {
+    capabilities: RwLock<Capabilities>,
+    channels: RwLock<Channels>,
+    config: Arc<Config>,
+    created: String,
+    lists: Mutex<Listings>,
+    max_users: Mutex<usize>,
+    motd: Mutex<Motd>,
+    name: String,
+    sessions: SharedSessions,
+    whowas: Mutex<Whowas>,
+}
 
 impl Service {
     pub fn reply(&self, connection: &Connection, request: &mut Request) {
         // Create a queue to store replies.
         let mut replies = Reply::new();
 
+        let id = connection.id();
+
+        // Any data from the client shows its connection is still alive.
+        if let Some(session) = self.session(id) {
+            if let Ok(mut session) = session.lock() {
+                session.set_last_seen(Instant::now());
+                session.set_ping_sent(None);
+            }
+        }
+
+        // Lines that were too long were dropped before reaching the request.
+        for _ in 0..request.overflow() {
+            if let Some(reply) = self.reply_input_too_long(id) {
+                replies = replies + reply;
+            }
+        }
+
+        // The connection has gone away so all that is left is to quit.
+        if let Some(reason) = request.disconnect() {
+            self.quit(id, reason);
+            return;
+        }
+
+        // Only requests made of complete lines are processed.
+        if !request.valid() {
+            self.send(id, &replies);
+            return;
+        }
+
         // Iterate over each message in the request.
+        let registering = !self.registered(id);
+        let mut welcomed = false;
+        let mut quit = None;
         for message in request.messages() {
-            println!("{:} -> {:?}", connection.id(), message.string()); // Remove me later
+            // Lines that cannot be parsed are dropped, only an invalid command
+            // is worth telling the client about.
+            let message = match message {
+                Ok(message) => message,
+                Err(ParseError::InvalidCommand(command)) => {
+                    if let Some(reply) = self.reply_unknown(id, command) {
+                        replies = replies + reply;
+                    }
+                    continue;
+                }
+                Err(_e) => continue,
+            };
+
+            // Nothing after a QUIT is processed.
+            if message.command().eq_ignore_ascii_case("QUIT") {
+                quit = Some(message);
+                break;
+            }
+
+            // Idle time is measured from the last message sent to someone.
+            let command = message.command().to_ascii_uppercase();
+            if command == "PRIVMSG" || command == "NOTICE" {
+                if let Some(session) = self.session(id) {
+                    if let Ok(mut session) = session.lock() {
+                        session.set_last_active(Instant::now());
+                    }
+                }
+            }
 
             // Generate reply based on message command using helper functions.
-            let reply = match message.command().to_uppercase().as_ref() {
-                "CAP" => self.reply_cap(connection.id(), message),
-                "NICK" => self.reply_nick(connection.id(), message),
-                "USER" => self.reply_user(connection.id(), message),
-                _ => None,
+            let reply = match command.as_ref() {
+                "CAP" => self.reply_cap(id, message),
+                "NICK" => self.reply_nick(id, message),
+                "PING" => self.reply_ping(id, message),
+                "PONG" => self.reply_pong(id, message),
+                "USER" => self.reply_user(id, message),
+                _ if !self.registered(id) => self.reply_not_registered(id),
+                "ADMIN" => self.reply_admin(id, message),
+                "AWAY" => self.reply_away(id, message),
+                "INFO" => self.reply_info(id, message),
+                "INVITE" => self.reply_invite(id, message),
+                "JOIN" => self.reply_join(id, message),
+                "KICK" => self.reply_kick(id, message),
+                "LIST" => self.reply_list(id, message),
+                "LUSERS" => self.reply_lusers(id, message),
+                "MODE" => self.reply_mode(id, message),
+                "MOTD" => self.reply_motd(id, message),
+                "NAMES" => self.reply_names(id, message),
+                "NOTICE" => self.reply_notice(id, message),
+                "OPER" => self.reply_oper(id, message),
+                "PART" => self.reply_part(id, message),
+                "PRIVMSG" => self.reply_privmsg(id, message),
+                "TAGMSG" => self.reply_tagmsg(id, message),
+                "TIME" => self.reply_time(id, message),
+                "TOPIC" => self.reply_topic(id, message),
+                "VERSION" => self.reply_version(id, message),
+                "WALLOPS" => self.reply_wallops(id, message),
+                "WHO" => self.reply_who(id, message),
+                "WHOIS" => self.reply_whois(id, message),
+                "WHOWAS" => self.reply_whowas(id, message),
+                _ => self.reply_unknown(id, message.command()),
             };
 
             // If a reply was generated add it to the replies queue.
-            match reply {
-                Some(reply) => {
-                    replies = replies + reply;
+            if let Some(reply) = reply {
+                replies = replies + reply;
+            }
+
+            // The welcome burst is followed by LUSERS and the MOTD.
+            if registering && !welcomed && self.registered(id) {
+                welcomed = true;
+                if let Some((nick, _prefix)) = self.identity(id) {
+                    replies = replies + self.lusers(&nick) + self.motd(&nick);
                 }
-                None => {}
             }
         }
 
-        // If there is a reply write it back to the connection stream.
-        for string in replies.strings().unwrap() {
-            println!("{:} <- {:?}", connection.id(), string); // Remove me later
+        // Write the replies back to the requesting session.
+        self.send(id, &replies);
+        self.resume_list(id);
 
-            match connection.stream().write(string.as_bytes()) {
-                Ok(_r) => {}
-                // If we can't write to stream shut it down.
-                Err(_e) => match connection.stream().shutdown(Shutdown::Both) {
-                    Ok(_r) => {}
-                    Err(_e) => {}
-                },
+        if registering && self.registered(id) {
+            self.notice_connecting(id);
+        }
+
+        if let Some(message) = quit {
+            self.reply_quit(id, message);
+        }
+    }
+
+    // Send a reply to every member of a channel except one session.
+    fn broadcast(&self, name: &str, reply: &Reply, except: Option<u64>) {
+        // Copy the member list so the channels lock is not held while writing.
+        let members = match self.channels.read() {
+            Ok(channels) => match channels.get(name) {
+                Some(channel) => channel.members().clone(),
+                None => return,
+            },
+            Err(_e) => return,
+        };
+
+        for member in members {
+            if Some(member) != except {
+                self.send(member, reply);
             }
         }
+    }
 
-        // Flush the connection stream after replies have been written.
-        if !replies.strings().unwrap().is_empty() {
-            match connection.stream().flush() {
-                Ok(_r) => {}
-                Err(_e) => {}
+    // Returns the nick and nick!user@host prefix of a session.
+    fn identity(&self, id: u64) -> Option<(String, String)> {
+        let session = self.session(id)?;
+        let session = match session.lock() {
+            Ok(session) => session,
+            Err(_e) => return None,
+        };
+        Some((session.nick().clone(), session.prefix()))
+    }
+
+    // Returns every other member of the given channels, each only once.
+    fn neighbours(&self, id: u64, joined: &[String]) -> BTreeSet<u64> {
+        let mut members = BTreeSet::new();
+        if let Ok(channels) = self.channels.read() {
+            for name in joined {
+                if let Some(channel) = channels.get(name) {
+                    members.extend(channel.members().iter().cloned());
+                }
+            }
+        }
+        members.remove(&id);
+        members
+    }
+
+    // Build a numeric reply from this server addressed to target. Numerics
+    // often echo tokens the client sent, so any middle parameter that could
+    // not be sent as one is replaced with *.
+    fn numeric(&self, code: &str, target: &str, parameters: &[&str]) -> Message {
+        let mut message = Message::new();
+        message.set_prefix(&self.name);
+        message.set_command(code);
+        let last = parameters.len();
+        for (i, parameter) in [target].iter().chain(parameters).enumerate() {
+            if i < last && !valid_middle(parameter) {
+                message.add_parameter("*");
+            } else {
+                message.add_parameter(parameter);
+            }
+        }
+        message
+    }
+
+    fn reply_nick(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+
+        // Hold the sessions lock for the whole change so two clients cannot
+        // claim the same nick at once.
+        let mut sessions = match self.sessions.write() {
+            Ok(sessions) => sessions,
+            Err(_e) => return None,
+        };
+        let session = sessions.get(id)?;
+        let mut session = match session.lock() {
+            Ok(session) => session,
+            Err(_e) => return None,
+        };
+        let target = session.target();
+
+        let nick = match message.parameters().first() {
+            Some(nick) if !nick.is_empty() => nick,
+            _ => {
+                reply.add_message(self.numeric("431", &target, &["No nickname given"]));
+                return Some(reply);
+            }
+        };
+
+        let unicode = self.config.casemapping().unicode();
+        if !valid_nick(nick, self.config.nick_length(), unicode) {
+            reply.add_message(self.numeric("432", &target, &[nick, "Erroneous nickname"]));
+            return Some(reply);
+        }
+
+        if nick == session.nick() {
+            return None;
+        }
+
+        // Reject the nick if another connection already owns it. 436 is left
+        // for nick collisions between linked servers, which cannot happen
+        // here.
+        match sessions.owner(nick) {
+            Some(owner) if owner != id => {
+                reply.add_message(self.numeric(
+                    "433",
+                    &target,
+                    &[nick, "Nickname is already in use"],
+                ));
+                return Some(reply);
             }
+            _ => {}
         }
+
+        // Registered clients may only change their nick every so often.
+        if let Some(nick_changed) = session.nick_changed() {
+            let nick_delay = self.config.nick_delay();
+            let elapsed = nick_changed.elapsed();
+            if elapsed < nick_delay {
+                let wait = nick_delay.saturating_sub(elapsed).as_secs() + 1;
+                let text = format!("Nick change too fast. Please wait {:} seconds", wait);
+                reply.add_message(self.numeric("438", &target, &[nick, &text]));
+                return Some(reply);
+            }
+        }
+
+        // Release the old nick and claim the new one.
+        sessions.rename(id, session.nick(), nick);
+
+        if !session.registered() {
+            session.set_nick(nick);
+            if session.ready() {
+                return Some(self.reply_welcome(&mut session));
+            }
+            return None;
+        }
+
+        self.remember(&session);
+        let prefix = session.prefix();
+        let joined: Vec<String> = session.channels().iter().cloned().collect();
+        session.set_nick(nick);
+        session.set_nick_changed(Some(Instant::now()));
+        drop(session);
+        drop(sessions);
+
+        // Tell the client and everyone sharing a channel with it once each.
+        reply.add_message(Message::from_parts(&prefix, "NICK", &[nick]));
+        for member in self.neighbours(id, &joined) {
+            self.send(member, &reply);
+        }
+        Some(reply)
     }
 
-    fn reply_cap(&self, _id: String, message: &Message) -> Option<Reply> {
-        match message.parameters().get(0) {
-            Some(p) => match p.to_uppercase().as_ref() {
-                "LS" => {
-                    let mut reply = Reply::new();
-                    let message = Message::from_string("CAP * LS : ".to_string());
-                    reply.add_message(message);
-                    Some(reply)
+    // Queue a reply on a session's send queue. The session stays locked while
+    // queueing so replies from different Workers do not interleave.
+    fn queue(&self, session: &Mutex<Session>, reply: &Reply) {
+        let mut session = match session.lock() {
+            Ok(session) => session,
+            Err(_e) => return,
+        };
+
+        // Clients with server-time get every message stamped with the time
+        // it was sent. Only clients with message-tags see client-only tags,
+        // or the TAGMSGs that carry nothing else.
+        let tags = session.has_capability("message-tags");
+        let time = if session.has_capability("server-time") {
+            Some(format_server_time())
+        } else {
+            None
+        };
+        let mut tagged = Reply::new();
+        for message in reply.messages() {
+            if !tags && message.command() == "TAGMSG" {
+                continue;
+            }
+            let mut message = message.clone();
+            if !tags {
+                for key in message.client_tags().keys() {
+                    message.remove_tag(key);
+                }
+            }
+            if let Some(time) = &time {
+                if message.tag("time").is_none() {
+                    message.set_tag("time", time);
                 }
-                _ => None,
+            }
+            tagged.add_message(message);
+        }
+        for string in &tagged.strings() {
+            session.send(string.as_bytes(), self.config.sendq());
+        }
+    }
+
+    fn registered(&self, id: u64) -> bool {
+        match self.session(id) {
+            Some(session) => match session.lock() {
+                Ok(session) => session.registered(),
+                Err(_e) => false,
             },
-            None => None,
+            None => false,
+        }
+    }
+
+    // Add who is using a session's nick to the WHOWAS history before they give
+    // it up.
+    fn remember(&self, session: &Session) {
+        let entry = WhowasEntry::new(
+            session.nick(),
+            session.user(),
+            session.hostname(),
+            session.realname(),
+            now(),
+        );
+        if let Ok(mut whowas) = self.whowas.lock() {
+            whowas.add(entry);
+        }
+    }
+
+    fn reply_input_too_long(&self, id: u64) -> Option<Reply> {
+        let session = self.session(id)?;
+        let target = match session.lock() {
+            Ok(session) => session.target(),
+            Err(_e) => return None,
+        };
+        let mut reply = Reply::new();
+        reply.add_message(self.numeric("417", &target, &["Input line was too long"]));
+        Some(reply)
+    }
+
+    fn reply_not_registered(&self, id: u64) -> Option<Reply> {
+        let session = self.session(id)?;
+        let target = match session.lock() {
+            Ok(session) => session.target(),
+            Err(_e) => return None,
+        };
+        let mut reply = Reply::new();
+        reply.add_message(self.numeric("451", &target, &["You have not registered"]));
+        Some(reply)
+    }
+
+    fn reply_unknown(&self, id: u64, command: &str) -> Option<Reply> {
+        let session = self.session(id)?;
+        let target = match session.lock() {
+            Ok(session) => session.target(),
+            Err(_e) => return None,
+        };
+        let mut reply = Reply::new();
+        reply.add_message(self.numeric("421", &target, &[command, "Unknown command"]));
+        Some(reply)
+    }
+
+    fn reply_user(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let session = self.session(id)?;
+        let mut session = match session.lock() {
+            Ok(session) => session,
+            Err(_e) => return None,
+        };
+
+        if session.registered() {
+            let target = session.target();
+            reply.add_message(self.numeric("462", &target, &["You may not reregister"]));
+            return Some(reply);
+        }
+
+        // USER <user> <mode> <unused> <realname>
+        let parameters = message.parameters();
+        if parameters.len() < 4 || parameters[0].is_empty() {
+            let target = session.target();
+            reply.add_message(self.numeric("461", &target, &["USER", "Not enough parameters"]));
+            return Some(reply);
+        }
+
+        // Usernames appear in every prefix so must not contain the characters
+        // that separate it from the nick and host. Long ones are shortened.
+        if !valid_user(&parameters[0]) {
+            let target = session.target();
+            reply.add_message(self.numeric("468", &target, &["Malformed username"]));
+            return Some(reply);
+        }
+        session.set_user(truncate(&parameters[0], self.config.user_length()));
+        session.set_realname(&parameters[3]);
+
+        // The mode is a bitmask where 4 asks for +w and 8 for +i.
+        if let Ok(mode) = parameters[1].parse::<u32>() {
+            if mode & 4 != 0 {
+                session.add_mode('w');
+            }
+            if mode & 8 != 0 {
+                session.add_mode('i');
+            }
+        }
+        if session.ready() {
+            Some(self.reply_welcome(&mut session))
+        } else {
+            None
         }
     }
 
-    fn reply_nick(&self, _id: String, _message: &Message) -> Option<Reply> {
-        None
+    // Mark the session registered and send the 001-004 welcome burst.
+    fn reply_welcome(&self, session: &mut Session) -> Reply {
+        let mut reply = Reply::new();
+        let nick = session.nick().clone();
+        let welcome = format!(
+            "Welcome to the Internet Relay Network {:}",
+            session.prefix()
+        );
+        let host = format!("Your host is {:}, running version {:}", self.name, VERSION);
+        let created = format!("This server was created {:}", self.created);
+
+        reply.add_message(self.numeric("001", &nick, &[&welcome]));
+        reply.add_message(self.numeric("002", &nick, &[&host]));
+        reply.add_message(self.numeric("003", &nick, &[&created]));
+        let prefix_modes: String = self
+            .prefixes()
+            .iter()
+            .map(|(mode, _symbol)| *mode)
+            .collect();
+        let parameter_modes = format!(
+            "{:}{:}{:}{:}",
+            LIST_MODES, KEY_MODES, LIMIT_MODES, prefix_modes
+        );
+        let channel_modes = format!("{:}{:}", parameter_modes, FLAG_MODES);
+        reply.add_message(self.numeric(
+            "004",
+            &nick,
+            &[
+                &self.name,
+                VERSION,
+                USER_MODES,
+                &channel_modes,
+                &parameter_modes,
+            ],
+        ));
+        reply = reply + self.reply_isupport(&nick);
+
+        session.set_registered(true);
+        session.set_signon(now());
+        reply
+    }
+
+    // Queue a reply on any session's send queue.
+    fn send(&self, id: u64, reply: &Reply) {
+        if let Some(session) = self.session(id) {
+            self.queue(&session, reply);
+        }
+    }
+
+    fn session(&self, id: u64) -> Option<Arc<Mutex<Session>>> {
+        match self.sessions.read() {
+            Ok(sessions) => sessions.get(id),
+            Err(_e) => None,
+        }
+    }
+
+    pub fn new(sessions: SharedSessions, config: Arc<Config>) -> Arc<Service> {
+        if let Ok(mut sessions) = sessions.write() {
+            sessions.set_casemapping(config.casemapping());
+        }
+        let motd = Motd::new(config.motd_path());
+        let whowas = Whowas::new(config.casemapping(), config.whowas_length());
+        Arc::new(Service {
+            capabilities: RwLock::new(Capabilities::new()),
+            channels: RwLock::new(Channels::new(config.casemapping())),
+            config,
+            created: format_time(now()),
+            lists: Mutex::new(Listings::new()),
+            max_users: Mutex::new(0),
+            motd: Mutex::new(motd),
+            name: "platform".to_string(),
+            sessions,
+            whowas: Mutex::new(whowas),
+        })
+    }
+}
+
+// Convert days since the Unix epoch to a (year, month, day) civil date.
+fn civil(days: u64) -> (i64, i64, i64) {
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Format the current time for the IRCv3 server-time tag, for example
+// 2011-10-19T16:40:51.620Z.
+fn format_server_time() -> String {
+    let millis = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_millis() as u64,
+        Err(_e) => 0,
+    };
+    let secs = millis / 1000;
+    let seconds = secs % 86400;
+    let (year, month, day) = civil(secs / 86400);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        millis % 1000
+    )
+}
+
+// Format seconds since the Unix epoch as a human readable UTC date.
+fn format_time(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let days = secs / 86400;
+    let seconds = secs % 86400;
+    let (year, month, day) = civil(days);
+
+    format!(
+        "{:} {:} {:} {:} at {:02}:{:02}:{:02} UTC",
+        DAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        day,
+        year,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+// Returns the current time in seconds since the Unix epoch.
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_e) => 0,
     }
+}
 
-    fn reply_user(&self, _id: String, _message: &Message) -> Option<Reply> {
-        None
+// Shorten text to at most length bytes without splitting a character.
+fn truncate(text: &str, length: usize) -> &str {
+    if text.len() <= length {
+        return text;
+    }
+    let mut end = length;
+    while !text.is_char_boundary(end) {
+        end -= 1;
     }
+    &text[..end]
+}
 
-    pub fn new() -> Arc<Service> {
-        Arc::new(Service {})
+// nickname = ( letter / special ) *( letter / digit / special / "-" ), where
+// letters and digits may be any Unicode ones when the casemapping allows it.
+fn valid_nick(nick: &str, nick_length: usize, unicode: bool) -> bool {
+    let special = |c: char| "[]\\`_^{|}".contains(c);
+    let letter = |c: char| {
+        if unicode {
+            c.is_alphabetic()
+        } else {
+            c.is_ascii_alphabetic()
+        }
+    };
+    let digit = |c: char| {
+        if unicode {
+            c.is_numeric()
+        } else {
+            c.is_ascii_digit()
+        }
+    };
+    let mut chars = nick.chars();
+    match chars.next() {
+        Some(c) if letter(c) || special(c) => {}
+        _ => return false,
     }
+    nick.chars().count() <= nick_length
+        && chars.all(|c| letter(c) || digit(c) || special(c) || c == '-')
+}
+
+// Usernames may contain anything but the prefix separators, spaces and
+// control characters.
+fn valid_user(user: &str) -> bool {
+    !user
+        .chars()
+        .any(|c| c == '!' || c == '@' || c == ' ' || c.is_control())
 }
@@ -0,0 +1,88 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::{truncate, Service};
+use std::collections::BTreeSet;
+
+impl Service {
+    // Tell the members with away-notify that a user is now away, or back when
+    // away is None.
+    pub(super) fn notify_away(&self, members: BTreeSet<u64>, prefix: &str, away: Option<&String>) {
+        let mut reply = Reply::new();
+        reply.add_message(match away {
+            Some(away) => Message::from_parts(prefix, "AWAY", &[away]),
+            None => Message::from_parts(prefix, "AWAY", &[]),
+        });
+
+        for member in members {
+            let notify = match self.session(member) {
+                Some(session) => match session.lock() {
+                    Ok(session) => session.has_capability("away-notify"),
+                    Err(_e) => false,
+                },
+                None => false,
+            };
+            if notify {
+                self.send(member, &reply);
+            }
+        }
+    }
+
+    pub(super) fn reply_away(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, prefix) = self.identity(id)?;
+
+        // AWAY with a message marks the client away, without one marks it back.
+        let away: Option<String> = match message.parameters().first() {
+            Some(text) if !text.is_empty() => {
+                Some(truncate(text, self.config.away_length()).to_string())
+            }
+            _ => None,
+        };
+        let (joined, changed) = match self.session(id)?.lock() {
+            Ok(mut session) => {
+                let changed = away.is_some() || session.away().is_some();
+                session.set_away(away.clone());
+                let joined: Vec<String> = session.channels().iter().cloned().collect();
+                (joined, changed)
+            }
+            Err(_e) => return None,
+        };
+
+        match away {
+            Some(_) => {
+                reply.add_message(self.numeric(
+                    "306",
+                    &nick,
+                    &["You have been marked as being away"],
+                ));
+            }
+            None => {
+                reply.add_message(self.numeric(
+                    "305",
+                    &nick,
+                    &["You are no longer marked as being away"],
+                ));
+            }
+        }
+        if changed {
+            self.notify_away(self.neighbours(id, &joined), &prefix, away.as_ref());
+        }
+        Some(reply)
+    }
+}
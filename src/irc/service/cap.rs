@@ -0,0 +1,246 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::Service;
+use crate::irc::BUFFER_SIZE;
+
+impl Service {
+    // Offer a new capability and announce it to clients with cap-notify.
+    pub fn add_capability(&self, name: &str, value: &str) {
+        match self.capabilities.write() {
+            Ok(mut capabilities) => capabilities.add(name, value),
+            Err(_e) => return,
+        }
+        self.notify_capability("NEW", name);
+    }
+
+    // Withdraw a capability, disabling it for every client that had it enabled
+    // and announcing it to clients with cap-notify.
+    pub fn remove_capability(&self, name: &str) {
+        match self.capabilities.write() {
+            Ok(mut capabilities) => {
+                if !capabilities.remove(name) {
+                    return;
+                }
+            }
+            Err(_e) => return,
+        }
+        self.notify_capability("DEL", name);
+    }
+
+    // Build CAP replies for a list of items, split across as many lines as
+    // needed to fit within BUFFER_SIZE. Clients using version 302 or later are
+    // told more lines follow with a * parameter.
+    fn cap_lines(&self, target: &str, subcommand: &str, items: &[String], version: u32) -> Reply {
+        let mut reply = Reply::new();
+
+        // :<server> CAP <target> <subcommand> * :<items>\r\n
+        let overhead = self.name.len() + target.len() + subcommand.len() + 14;
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        for item in items {
+            if version >= 302
+                && !line.is_empty()
+                && overhead + line.len() + item.len() + 1 > BUFFER_SIZE
+            {
+                lines.push(line);
+                line = String::new();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(item);
+        }
+        lines.push(line);
+
+        let last = lines.len() - 1;
+        for (i, line) in lines.iter().enumerate() {
+            let message = if i < last {
+                Message::from_parts(&self.name, "CAP", &[target, subcommand, "*", line])
+            } else {
+                Message::from_parts(&self.name, "CAP", &[target, subcommand, line])
+            };
+            reply.add_message(message);
+        }
+        reply
+    }
+
+    // Send CAP NEW or CAP DEL for a capability to every client with cap-notify.
+    fn notify_capability(&self, subcommand: &str, name: &str) {
+        let ids = match self.sessions.read() {
+            Ok(sessions) => sessions.ids(),
+            Err(_e) => return,
+        };
+
+        for id in ids {
+            let session = match self.session(id) {
+                Some(session) => session,
+                None => continue,
+            };
+            let (target, version) = match session.lock() {
+                Ok(mut session) => {
+                    if subcommand == "DEL" {
+                        session.remove_capability(name);
+                    }
+                    if !session.has_capability("cap-notify") {
+                        continue;
+                    }
+                    (session.target(), session.cap_version())
+                }
+                Err(_e) => continue,
+            };
+
+            let item = match self.capabilities.read() {
+                Ok(capabilities) => match capabilities.get(name) {
+                    Some(capability) => capability.string(version),
+                    None => name.to_string(),
+                },
+                Err(_e) => continue,
+            };
+            let mut reply = Reply::new();
+            reply.add_message(Message::from_parts(
+                &self.name,
+                "CAP",
+                &[&target, subcommand, &item],
+            ));
+            self.send(id, &reply);
+        }
+    }
+
+    pub(super) fn reply_cap(&self, id: u64, message: &Message) -> Option<Reply> {
+        let session = self.session(id)?;
+        let mut session = match session.lock() {
+            Ok(session) => session,
+            Err(_e) => return None,
+        };
+        let target = session.target();
+        let mut reply = Reply::new();
+
+        let subcommand = match message.parameters().first() {
+            Some(subcommand) => subcommand.to_ascii_uppercase(),
+            None => {
+                reply.add_message(self.numeric("461", &target, &["CAP", "Not enough parameters"]));
+                return Some(reply);
+            }
+        };
+
+        match subcommand.as_ref() {
+            "LS" => {
+                // Hold registration open until the client sends CAP END.
+                if !session.registered() {
+                    session.set_cap_negotiating(true);
+                }
+
+                // Version 302 clients implicitly get cap-notify.
+                let version = match message.parameters().get(1) {
+                    Some(version) => version.parse().unwrap_or(0),
+                    None => 0,
+                };
+                if version > session.cap_version() {
+                    session.set_cap_version(version);
+                }
+                if session.cap_version() >= 302 {
+                    session.add_capability("cap-notify");
+                }
+
+                let version = session.cap_version();
+                let items: Vec<String> = match self.capabilities.read() {
+                    Ok(capabilities) => capabilities
+                        .capabilities()
+                        .iter()
+                        .map(|capability| capability.string(version))
+                        .collect(),
+                    Err(_e) => return None,
+                };
+                Some(self.cap_lines(&target, "LS", &items, version))
+            }
+            "LIST" => {
+                let mut items: Vec<String> = session.capabilities().iter().cloned().collect();
+                items.sort();
+                Some(self.cap_lines(&target, "LIST", &items, session.cap_version()))
+            }
+            "REQ" => {
+                if !session.registered() {
+                    session.set_cap_negotiating(true);
+                }
+
+                let requested = match message.parameters().get(1) {
+                    Some(requested) => requested.clone(),
+                    None => String::new(),
+                };
+
+                // The whole set is either acknowledged or rejected.
+                let acceptable = match self.capabilities.read() {
+                    Ok(capabilities) => {
+                        let mut names = requested
+                            .split(' ')
+                            .filter(|name| !name.is_empty())
+                            .peekable();
+                        names.peek().is_some()
+                            && names.all(|name| match name.strip_prefix('-') {
+                                // cap-notify cannot be disabled once version 302
+                                // has been negotiated.
+                                Some("cap-notify") => session.cap_version() < 302,
+                                Some(name) => capabilities.contains(name),
+                                None => capabilities.contains(name),
+                            })
+                    }
+                    Err(_e) => return None,
+                };
+
+                if !acceptable {
+                    reply.add_message(Message::from_parts(
+                        &self.name,
+                        "CAP",
+                        &[&target, "NAK", &requested],
+                    ));
+                    return Some(reply);
+                }
+
+                for name in requested.split(' ').filter(|name| !name.is_empty()) {
+                    match name.strip_prefix('-') {
+                        Some(name) => session.remove_capability(name),
+                        None => session.add_capability(name),
+                    }
+                }
+                reply.add_message(Message::from_parts(
+                    &self.name,
+                    "CAP",
+                    &[&target, "ACK", &requested],
+                ));
+                Some(reply)
+            }
+            "END" => {
+                session.set_cap_negotiating(false);
+                if session.ready() {
+                    Some(self.reply_welcome(&mut session))
+                } else {
+                    None
+                }
+            }
+            _ => {
+                reply.add_message(self.numeric(
+                    "410",
+                    &target,
+                    &[&subcommand, "Invalid CAP command"],
+                ));
+                Some(reply)
+            }
+        }
+    }
+}
@@ -0,0 +1,499 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::channel::valid_channel;
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::mode::rank;
+use crate::irc::service::{now, truncate, Service};
+use crate::irc::BUFFER_SIZE;
+use std::collections::BTreeSet;
+
+impl Service {
+    // Build the 353 and 366 replies listing the members of a channel, split
+    // across as many 353 lines as needed to fit within BUFFER_SIZE. Secret
+    // channels only list their members to other members.
+    pub(super) fn names(&self, id: u64, nick: &str, name: &str) -> Reply {
+        let mut reply = Reply::new();
+
+        // Clients with multi-prefix see every prefix a member holds.
+        let all = match self.session(id) {
+            Some(session) => match session.lock() {
+                Ok(session) => session.has_capability("multi-prefix"),
+                Err(_e) => false,
+            },
+            None => false,
+        };
+
+        // Copy the member list so the channels lock is not held while
+        // looking up sessions.
+        let (name, symbol, joined, members) = match self.channels.read() {
+            Ok(channels) => match channels.get(name) {
+                Some(channel) if !channel.has_mode('s') || channel.has_member(id) => (
+                    channel.name().clone(),
+                    channel.symbol(),
+                    channel.has_member(id),
+                    channel
+                        .members()
+                        .iter()
+                        .map(|member| (*member, channel.prefix(*member, all)))
+                        .collect(),
+                ),
+                _ => (name.to_string(), "=", false, Vec::new()),
+            },
+            Err(_e) => return reply,
+        };
+
+        // Invisible members are only listed to others in the channel.
+        let mut nicks = Vec::new();
+        if let Ok(sessions) = self.sessions.read() {
+            for (member, prefix) in members {
+                if let Some(session) = sessions.get(member) {
+                    if let Ok(session) = session.lock() {
+                        if joined || !session.has_mode('i') {
+                            nicks.push(format!("{:}{:}", prefix, session.nick()));
+                        }
+                    }
+                }
+            }
+        }
+
+        // :<server> 353 <nick> = <channel> :<names>\r\n
+        let overhead = self.name.len() + nick.len() + name.len() + 14;
+        let mut line = String::new();
+        for n in nicks {
+            if !line.is_empty() && overhead + line.len() + n.len() + 1 > BUFFER_SIZE {
+                reply.add_message(self.numeric("353", nick, &[symbol, &name, &line]));
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&n);
+        }
+        if !line.is_empty() {
+            reply.add_message(self.numeric("353", nick, &[symbol, &name, &line]));
+        }
+        reply.add_message(self.numeric("366", nick, &[&name, "End of /NAMES list"]));
+        reply
+    }
+
+    // Remove a session from a channel. Returns the channel's display name if
+    // the session was a member.
+    pub(super) fn part(&self, id: u64, name: &str) -> Option<String> {
+        let name = {
+            let mut channels = match self.channels.write() {
+                Ok(channels) => channels,
+                Err(_e) => return None,
+            };
+            let display = channels.get(name)?.name().clone();
+            if !channels.part(name, id) {
+                return None;
+            }
+            display
+        };
+
+        let session = self.session(id)?;
+        if let Ok(mut session) = session.lock() {
+            session.remove_channel(&name);
+        }
+        Some(name)
+    }
+
+    // Send a message to every remaining member of a channel.
+    fn relay(&self, name: &str, message: &Message) {
+        let mut relay = Reply::new();
+        relay.add_message(message.clone());
+        self.broadcast(name, &relay, None);
+    }
+
+    pub(super) fn reply_join(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, prefix) = self.identity(id)?;
+
+        let names = match message.parameters().first() {
+            Some(names) if !names.is_empty() => names,
+            _ => {
+                reply.add_message(self.numeric("461", &nick, &["JOIN", "Not enough parameters"]));
+                return Some(reply);
+            }
+        };
+
+        // JOIN 0 leaves every channel the client is in.
+        if names == "0" {
+            let joined: Vec<String> = match self.session(id)?.lock() {
+                Ok(session) => session.channels().iter().cloned().collect(),
+                Err(_e) => return None,
+            };
+            for name in joined {
+                if let Some(name) = self.part(id, &name) {
+                    let message = Message::from_parts(&prefix, "PART", &[&name]);
+                    self.relay(&name, &message);
+                    reply.add_message(message);
+                }
+            }
+            return Some(reply);
+        }
+
+        // Keys are matched to channels by position.
+        let keys: Vec<&str> = match message.parameters().get(1) {
+            Some(keys) => keys.split(',').collect(),
+            None => Vec::new(),
+        };
+
+        for (i, name) in names.split(',').enumerate() {
+            if !valid_channel(name, self.config.channel_length()) {
+                reply.add_message(self.numeric("403", &nick, &[name, "No such channel"]));
+                continue;
+            }
+            let key = keys.get(i).cloned().unwrap_or("");
+
+            let (name, topic) = {
+                let mut channels = match self.channels.write() {
+                    Ok(channels) => channels,
+                    Err(_e) => return None,
+                };
+                let channel = channels.get_or_create(name, now());
+                if channel.has_member(id) {
+                    continue;
+                }
+                let error = if !channel.key().is_empty() && channel.key() != key {
+                    Some(("475", "Cannot join channel (+k)"))
+                } else if channel.limit() > 0 && channel.members().len() >= channel.limit() {
+                    Some(("471", "Cannot join channel (+l)"))
+                } else if channel.has_mode('i')
+                    && !channel.invited(id, self.config.invite_expiry())
+                    && !self.listed(channel, 'I', &prefix)
+                {
+                    Some(("473", "Cannot join channel (+i)"))
+                } else if self.banned(channel, &prefix) {
+                    Some(("474", "Cannot join channel (+b)"))
+                } else {
+                    None
+                };
+                if let Some((code, text)) = error {
+                    let name = channel.name().clone();
+                    reply.add_message(self.numeric(code, &nick, &[&name, text]));
+                    continue;
+                }
+
+                // New channels start out +nt with their creator as operator.
+                if channel.members().is_empty() {
+                    channel.add_mode('n');
+                    channel.add_mode('t');
+                    channel.add_status(id, 'o');
+                }
+                channel.add_member(id);
+                channel.remove_invite(id);
+                let topic = if channel.topic().is_empty() {
+                    None
+                } else {
+                    Some((
+                        channel.topic().clone(),
+                        channel.topic_setter().clone(),
+                        channel.topic_time(),
+                    ))
+                };
+                (channel.name().clone(), topic)
+            };
+
+            let mut away = None;
+            if let Some(session) = self.session(id) {
+                if let Ok(mut session) = session.lock() {
+                    session.add_channel(&name);
+                    away = session.away().cloned();
+                }
+            }
+
+            // Tell the existing members about the new one.
+            let message = Message::from_parts(&prefix, "JOIN", &[&name]);
+            let mut relay = Reply::new();
+            relay.add_message(message.clone());
+            self.broadcast(&name, &relay, Some(id));
+
+            // Members with away-notify are told straight away that the new
+            // member is away.
+            if away.is_some() {
+                let mut members = match self.channels.read() {
+                    Ok(channels) => match channels.get(&name) {
+                        Some(channel) => channel.members().clone(),
+                        None => BTreeSet::new(),
+                    },
+                    Err(_e) => BTreeSet::new(),
+                };
+                members.remove(&id);
+                self.notify_away(members, &prefix, away.as_ref());
+            }
+
+            reply.add_message(message);
+            if let Some((topic, setter, time)) = topic {
+                reply.add_message(self.numeric("332", &nick, &[&name, &topic]));
+                reply.add_message(self.numeric("333", &nick, &[&name, &setter, &time.to_string()]));
+            }
+            reply = reply + self.names(id, &nick, &name);
+        }
+
+        Some(reply)
+    }
+
+    pub(super) fn reply_kick(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, prefix) = self.identity(id)?;
+
+        let parameters = message.parameters();
+        if parameters.len() < 2 || parameters[0].is_empty() || parameters[1].is_empty() {
+            reply.add_message(self.numeric("461", &nick, &["KICK", "Not enough parameters"]));
+            return Some(reply);
+        }
+
+        // KICK <channel> <user>[,<user>] kicks every user from one channel,
+        // otherwise channels and users are paired up by position.
+        let names: Vec<&str> = parameters[0].split(',').collect();
+        let users: Vec<&str> = parameters[1].split(',').collect();
+        if names.len() != 1 && names.len() != users.len() {
+            reply.add_message(self.numeric("461", &nick, &["KICK", "Not enough parameters"]));
+            return Some(reply);
+        }
+        let reason: String = match parameters.get(2) {
+            Some(reason) if !reason.is_empty() => {
+                truncate(reason, self.config.kick_length()).to_string()
+            }
+            _ => nick.clone(),
+        };
+
+        for (i, user) in users.iter().enumerate() {
+            let name = if names.len() == 1 { names[0] } else { names[i] };
+
+            let target = match self.sessions.read() {
+                Ok(sessions) => sessions.find(user),
+                Err(_e) => return None,
+            };
+            let target = match target {
+                Some(session) => match session.lock() {
+                    Ok(session) => Some((session.id(), session.nick().clone())),
+                    Err(_e) => None,
+                },
+                None => None,
+            };
+
+            // Operators may kick anyone and half-operators anyone ranked no
+            // higher than themselves.
+            let (name, error) = match self.channels.read() {
+                Ok(channels) => match channels.get(name) {
+                    Some(channel) => {
+                        let display = channel.name().clone();
+                        let error = if !channel.has_member(id) {
+                            Some(self.numeric(
+                                "442",
+                                &nick,
+                                &[&display, "You're not on that channel"],
+                            ))
+                        } else if channel.rank(id) < rank('h') {
+                            Some(self.numeric(
+                                "482",
+                                &nick,
+                                &[&display, "You're not channel operator"],
+                            ))
+                        } else {
+                            match &target {
+                                None => Some(self.numeric(
+                                    "401",
+                                    &nick,
+                                    &[user, "No such nick/channel"],
+                                )),
+                                Some((member, target)) if !channel.has_member(*member) => {
+                                    Some(self.numeric(
+                                        "441",
+                                        &nick,
+                                        &[target, &display, "They aren't on that channel"],
+                                    ))
+                                }
+                                Some((member, _target))
+                                    if channel.rank(*member) > channel.rank(id) =>
+                                {
+                                    Some(self.numeric(
+                                        "482",
+                                        &nick,
+                                        &[&display, "You're not channel operator"],
+                                    ))
+                                }
+                                _ => None,
+                            }
+                        };
+                        (display, error)
+                    }
+                    None => (
+                        name.to_string(),
+                        Some(self.numeric("403", &nick, &[name, "No such channel"])),
+                    ),
+                },
+                Err(_e) => return None,
+            };
+            if let Some(error) = error {
+                reply.add_message(error);
+                continue;
+            }
+            let (member, target) = target?;
+
+            // Everyone in the channel, the kicked user included, sees the KICK
+            // before they are removed.
+            let message = Message::from_parts(&prefix, "KICK", &[&name, &target, &reason]);
+            let mut relay = Reply::new();
+            relay.add_message(message.clone());
+            self.broadcast(&name, &relay, Some(id));
+            reply.add_message(message);
+            self.part(member, &name);
+        }
+
+        Some(reply)
+    }
+
+    pub(super) fn reply_names(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, _prefix) = self.identity(id)?;
+
+        match message.parameters().first() {
+            Some(names) if !names.is_empty() => {
+                for name in names.split(',') {
+                    reply = reply + self.names(id, &nick, name);
+                }
+            }
+            _ => {
+                reply.add_message(self.numeric("366", &nick, &["*", "End of /NAMES list"]));
+            }
+        }
+
+        Some(reply)
+    }
+
+    pub(super) fn reply_part(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, prefix) = self.identity(id)?;
+
+        let names = match message.parameters().first() {
+            Some(names) if !names.is_empty() => names,
+            _ => {
+                reply.add_message(self.numeric("461", &nick, &["PART", "Not enough parameters"]));
+                return Some(reply);
+            }
+        };
+        let reason = message.parameters().get(1);
+
+        for name in names.split(',') {
+            let exists = match self.channels.read() {
+                Ok(channels) => channels.get(name).is_some(),
+                Err(_e) => return None,
+            };
+            if !exists {
+                reply.add_message(self.numeric("403", &nick, &[name, "No such channel"]));
+                continue;
+            }
+
+            match self.part(id, name) {
+                Some(name) => {
+                    let message = match reason {
+                        Some(reason) => Message::from_parts(&prefix, "PART", &[&name, reason]),
+                        None => Message::from_parts(&prefix, "PART", &[&name]),
+                    };
+                    self.relay(&name, &message);
+                    reply.add_message(message);
+                }
+                None => {
+                    reply.add_message(self.numeric(
+                        "442",
+                        &nick,
+                        &[name, "You're not on that channel"],
+                    ));
+                }
+            }
+        }
+
+        Some(reply)
+    }
+
+    pub(super) fn reply_topic(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, prefix) = self.identity(id)?;
+
+        let name = match message.parameters().first() {
+            Some(name) if !name.is_empty() => name,
+            _ => {
+                reply.add_message(self.numeric("461", &nick, &["TOPIC", "Not enough parameters"]));
+                return Some(reply);
+            }
+        };
+
+        let mut channels = match self.channels.write() {
+            Ok(channels) => channels,
+            Err(_e) => return None,
+        };
+        let channel = match channels.get_mut(name) {
+            Some(channel) => channel,
+            None => {
+                reply.add_message(self.numeric("403", &nick, &[name, "No such channel"]));
+                return Some(reply);
+            }
+        };
+        let name = channel.name().clone();
+
+        match message.parameters().get(1) {
+            // TOPIC <channel> <topic> sets the topic.
+            Some(topic) => {
+                if !channel.has_member(id) {
+                    reply.add_message(self.numeric(
+                        "442",
+                        &nick,
+                        &[&name, "You're not on that channel"],
+                    ));
+                    return Some(reply);
+                }
+                if channel.has_mode('t') && channel.rank(id) < rank('h') {
+                    reply.add_message(self.numeric(
+                        "482",
+                        &nick,
+                        &[&name, "You're not channel operator"],
+                    ));
+                    return Some(reply);
+                }
+                let topic = truncate(topic, self.config.topic_length());
+                channel.set_topic(topic, &prefix, now());
+                drop(channels);
+
+                let message = Message::from_parts(&prefix, "TOPIC", &[&name, topic]);
+                let mut relay = Reply::new();
+                relay.add_message(message.clone());
+                self.broadcast(&name, &relay, Some(id));
+                reply.add_message(message);
+            }
+            // TOPIC <channel> returns the current topic.
+            None => {
+                if channel.topic().is_empty() {
+                    reply.add_message(self.numeric("331", &nick, &[&name, "No topic is set"]));
+                } else {
+                    let time = channel.topic_time().to_string();
+                    reply.add_message(self.numeric("332", &nick, &[&name, channel.topic()]));
+                    reply.add_message(self.numeric(
+                        "333",
+                        &nick,
+                        &[&name, channel.topic_setter(), &time],
+                    ));
+                }
+            }
+        }
+
+        Some(reply)
+    }
+}
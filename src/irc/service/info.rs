@@ -0,0 +1,219 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::mask::matches;
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::{format_time, now, Service, VERSION};
+use crate::irc::BUFFER_SIZE;
+
+impl Service {
+    // Build the LUSERS replies from the sessions and channels as they are now.
+    pub(super) fn lusers(&self, nick: &str) -> Reply {
+        let mut reply = Reply::new();
+
+        let (mut visible, mut invisible, mut operators, mut unknown) = (0, 0, 0, 0);
+        if let Ok(sessions) = self.sessions.read() {
+            for id in sessions.ids() {
+                if let Some(session) = sessions.get(id) {
+                    if let Ok(session) = session.lock() {
+                        if !session.registered() {
+                            unknown += 1;
+                            continue;
+                        }
+                        if session.has_mode('i') {
+                            invisible += 1;
+                        } else {
+                            visible += 1;
+                        }
+                        if session.has_mode('o') {
+                            operators += 1;
+                        }
+                    }
+                }
+            }
+        }
+        let channels = match self.channels.read() {
+            Ok(channels) => channels.len(),
+            Err(_e) => 0,
+        };
+        let users = visible + invisible;
+        let max = match self.max_users.lock() {
+            Ok(mut max) => {
+                *max = (*max).max(users);
+                *max
+            }
+            Err(_e) => users,
+        };
+
+        let text = format!(
+            "There are {:} users and {:} invisible on 1 servers",
+            visible, invisible
+        );
+        reply.add_message(self.numeric("251", nick, &[&text]));
+        if operators > 0 {
+            let operators = operators.to_string();
+            reply.add_message(self.numeric("252", nick, &[&operators, "operator(s) online"]));
+        }
+        if unknown > 0 {
+            let unknown = unknown.to_string();
+            reply.add_message(self.numeric("253", nick, &[&unknown, "unknown connection(s)"]));
+        }
+        if channels > 0 {
+            let channels = channels.to_string();
+            reply.add_message(self.numeric("254", nick, &[&channels, "channels formed"]));
+        }
+        let text = format!("I have {:} clients and 0 servers", users);
+        reply.add_message(self.numeric("255", nick, &[&text]));
+        let (users, max) = (users.to_string(), max.to_string());
+        let text = format!("Current local users {:}, max {:}", users, max);
+        reply.add_message(self.numeric("265", nick, &[&users, &max, &text]));
+        let text = format!("Current global users {:}, max {:}", users, max);
+        reply.add_message(self.numeric("266", nick, &[&users, &max, &text]));
+        reply
+    }
+
+    // Build the MOTD replies, or 422 if there is no message of the day.
+    pub(super) fn motd(&self, nick: &str) -> Reply {
+        let mut reply = Reply::new();
+        let mut motd = match self.motd.lock() {
+            Ok(motd) => motd,
+            Err(_e) => return reply,
+        };
+        let lines = match motd.lines() {
+            Some(lines) => lines,
+            None => {
+                reply.add_message(self.numeric("422", nick, &["MOTD File is missing"]));
+                return reply;
+            }
+        };
+
+        // :<server> 372 <nick> :- <line>\r\n
+        let room = BUFFER_SIZE - (self.name.len() + nick.len() + 12);
+        let start = format!("- {:} Message of the day - ", self.name);
+        reply.add_message(self.numeric("375", nick, &[&start]));
+        for line in lines {
+            let mut line = format!("- {:}", line);
+            while line.len() > room {
+                line.pop();
+            }
+            reply.add_message(self.numeric("372", nick, &[&line]));
+        }
+        reply.add_message(self.numeric("376", nick, &["End of /MOTD command."]));
+        reply
+    }
+
+    // Informational commands may name the server to ask, which can only ever
+    // be this one. Returns a 402 reply when they name another.
+    fn remote(&self, nick: &str, message: &Message) -> Option<Reply> {
+        let target = match message.parameters().first() {
+            Some(target) if !target.is_empty() => target,
+            _ => return None,
+        };
+        if matches(
+            &target.to_ascii_lowercase(),
+            &self.name.to_ascii_lowercase(),
+        ) {
+            return None;
+        }
+        let mut reply = Reply::new();
+        reply.add_message(self.numeric("402", nick, &[target, "No such server"]));
+        Some(reply)
+    }
+
+    pub(super) fn reply_admin(&self, id: u64, message: &Message) -> Option<Reply> {
+        let (nick, _prefix) = self.identity(id)?;
+        if let Some(reply) = self.remote(&nick, message) {
+            return Some(reply);
+        }
+
+        let mut reply = Reply::new();
+        let location = self.config.admin_location();
+        let organization = self.config.admin_organization();
+        let email = self.config.admin_email();
+        if location.is_empty() && organization.is_empty() && email.is_empty() {
+            reply.add_message(self.numeric(
+                "423",
+                &nick,
+                &[&self.name, "No administrative info available"],
+            ));
+            return Some(reply);
+        }
+        reply.add_message(self.numeric("256", &nick, &[&self.name, "Administrative info"]));
+        reply.add_message(self.numeric("257", &nick, &[location]));
+        reply.add_message(self.numeric("258", &nick, &[organization]));
+        reply.add_message(self.numeric("259", &nick, &[email]));
+        Some(reply)
+    }
+
+    pub(super) fn reply_info(&self, id: u64, message: &Message) -> Option<Reply> {
+        let (nick, _prefix) = self.identity(id)?;
+        if let Some(reply) = self.remote(&nick, message) {
+            return Some(reply);
+        }
+
+        let mut reply = Reply::new();
+        for line in self.config.info().lines() {
+            reply.add_message(self.numeric("371", &nick, &[line]));
+        }
+        reply.add_message(self.numeric("374", &nick, &["End of INFO list"]));
+        Some(reply)
+    }
+
+    pub(super) fn reply_lusers(&self, id: u64, _message: &Message) -> Option<Reply> {
+        let (nick, _prefix) = self.identity(id)?;
+        Some(self.lusers(&nick))
+    }
+
+    pub(super) fn reply_motd(&self, id: u64, message: &Message) -> Option<Reply> {
+        let (nick, _prefix) = self.identity(id)?;
+        if let Some(reply) = self.remote(&nick, message) {
+            return Some(reply);
+        }
+        Some(self.motd(&nick))
+    }
+
+    pub(super) fn reply_time(&self, id: u64, message: &Message) -> Option<Reply> {
+        let (nick, _prefix) = self.identity(id)?;
+        if let Some(reply) = self.remote(&nick, message) {
+            return Some(reply);
+        }
+
+        let mut reply = Reply::new();
+        let time = now();
+        reply.add_message(self.numeric(
+            "391",
+            &nick,
+            &[&self.name, &time.to_string(), "0", &format_time(time)],
+        ));
+        Some(reply)
+    }
+
+    pub(super) fn reply_version(&self, id: u64, message: &Message) -> Option<Reply> {
+        let (nick, _prefix) = self.identity(id)?;
+        if let Some(reply) = self.remote(&nick, message) {
+            return Some(reply);
+        }
+
+        let mut reply = Reply::new();
+        reply.add_message(self.numeric(
+            "351",
+            &nick,
+            &[VERSION, &self.name, self.config.description()],
+        ));
+        Some(reply + self.reply_isupport(&nick))
+    }
+}
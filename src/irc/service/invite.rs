@@ -0,0 +1,140 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::mode::rank;
+use crate::irc::service::Service;
+use std::time::Instant;
+
+impl Service {
+    // Returns the channels a session holds an unexpired invite to.
+    fn invites(&self, id: u64) -> Vec<String> {
+        let expiry = self.config.invite_expiry();
+        match self.channels.read() {
+            Ok(channels) => channels
+                .channels()
+                .filter(|channel| channel.invited(id, expiry))
+                .map(|channel| channel.name().clone())
+                .collect(),
+            Err(_e) => Vec::new(),
+        }
+    }
+
+    pub(super) fn reply_invite(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, prefix) = self.identity(id)?;
+
+        // INVITE on its own lists the channels the client is invited to.
+        let parameters = message.parameters();
+        if parameters.is_empty() {
+            for name in self.invites(id) {
+                reply.add_message(self.numeric("336", &nick, &[&name]));
+            }
+            reply.add_message(self.numeric("337", &nick, &["End of /INVITE list"]));
+            return Some(reply);
+        }
+        if parameters.len() < 2 || parameters[0].is_empty() || parameters[1].is_empty() {
+            reply.add_message(self.numeric("461", &nick, &["INVITE", "Not enough parameters"]));
+            return Some(reply);
+        }
+        let (user, name) = (&parameters[0], &parameters[1]);
+
+        let target = match self.sessions.read() {
+            Ok(sessions) => sessions.find(user),
+            Err(_e) => return None,
+        };
+        let (member, target) = match target {
+            Some(session) => match session.lock() {
+                Ok(session) => (session.id(), session.nick().clone()),
+                Err(_e) => return None,
+            },
+            None => {
+                reply.add_message(self.numeric("401", &nick, &[user, "No such nick/channel"]));
+                return Some(reply);
+            }
+        };
+
+        // Only channel operators may invite to +i channels. Operators other
+        // than the inviter are told about the invite with invite-notify.
+        let (name, operators) = {
+            let mut channels = match self.channels.write() {
+                Ok(channels) => channels,
+                Err(_e) => return None,
+            };
+            let channel = match channels.get_mut(name) {
+                Some(channel) => channel,
+                None => {
+                    reply.add_message(self.numeric("403", &nick, &[name, "No such channel"]));
+                    return Some(reply);
+                }
+            };
+            let name = channel.name().clone();
+            if !channel.has_member(id) {
+                reply.add_message(self.numeric(
+                    "442",
+                    &nick,
+                    &[&name, "You're not on that channel"],
+                ));
+                return Some(reply);
+            }
+            if channel.has_mode('i') && channel.rank(id) < rank('h') {
+                reply.add_message(self.numeric(
+                    "482",
+                    &nick,
+                    &[&name, "You're not channel operator"],
+                ));
+                return Some(reply);
+            }
+            if channel.has_member(member) {
+                reply.add_message(self.numeric(
+                    "443",
+                    &nick,
+                    &[&target, &name, "is already on channel"],
+                ));
+                return Some(reply);
+            }
+            channel.expire_invites(self.config.invite_expiry());
+            channel.add_invite(member, Instant::now());
+            let operators: Vec<u64> = channel
+                .members()
+                .iter()
+                .filter(|m| **m != id && channel.rank(**m) >= rank('h'))
+                .cloned()
+                .collect();
+            (name, operators)
+        };
+
+        let mut invite = Reply::new();
+        invite.add_message(Message::from_parts(&prefix, "INVITE", &[&target, &name]));
+        self.send(member, &invite);
+        for operator in operators {
+            let notify = match self.session(operator) {
+                Some(session) => match session.lock() {
+                    Ok(session) => session.has_capability("invite-notify"),
+                    Err(_e) => false,
+                },
+                None => false,
+            };
+            if notify {
+                self.send(operator, &invite);
+            }
+        }
+
+        reply.add_message(self.numeric("341", &nick, &[&target, &name]));
+        Some(reply)
+    }
+}
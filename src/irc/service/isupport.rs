@@ -0,0 +1,105 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::channel::{FLAG_MODES, KEY_MODES, LIMIT_MODES, LIST_MODES};
+use crate::irc::message::Reply;
+use crate::irc::service::Service;
+use crate::irc::{BUFFER_SIZE, CHANNEL_TYPES};
+
+// Leave room for the target and the trailing text within 15 parameters.
+const TOKENS_PER_LINE: usize = 13;
+
+impl Service {
+    // Returns the ISUPPORT tokens describing how this server is configured.
+    fn isupport(&self) -> Vec<String> {
+        let prefixes = self.prefixes();
+        let modes: String = prefixes.iter().map(|(mode, _symbol)| *mode).collect();
+        let symbols: String = prefixes.iter().map(|(_mode, symbol)| *symbol).collect();
+
+        vec![
+            format!("AWAYLEN={:}", self.config.away_length()),
+            format!("CASEMAPPING={:}", self.config.casemapping().name()),
+            format!(
+                "CHANMODES={:},{:},{:},{:}",
+                LIST_MODES, KEY_MODES, LIMIT_MODES, FLAG_MODES
+            ),
+            format!("CHANNELLEN={:}", self.config.channel_length()),
+            format!("CHANTYPES={:}", CHANNEL_TYPES),
+            "ELIST=CMNTU".to_string(),
+            "EXCEPTS=e".to_string(),
+            "INVEX=I".to_string(),
+            format!("KICKLEN={:}", self.config.kick_length()),
+            format!("MAXLIST={:}:{:}", LIST_MODES, self.config.max_list()),
+            format!("MAXTARGETS={:}", self.config.max_targets()),
+            format!("MODES={:}", self.config.modes()),
+            format!("NETWORK={:}", escape_isupport_value(self.config.network())),
+            format!("NICKLEN={:}", self.config.nick_length()),
+            format!("PREFIX=({:}){:}", modes, symbols),
+            "SAFELIST".to_string(),
+            format!("TOPICLEN={:}", self.config.topic_length()),
+            format!("USERLEN={:}", self.config.user_length()),
+            "WHOX".to_string(),
+        ]
+    }
+
+    // Build the 005 replies, splitting the tokens over as many lines as
+    // needed for each to fit within BUFFER_SIZE.
+    pub(super) fn reply_isupport(&self, target: &str) -> Reply {
+        let mut reply = Reply::new();
+        let text = "are supported by this server";
+
+        // :<server> 005 <target> <tokens> :<text>\r\n
+        let overhead = self.name.len() + target.len() + text.len() + 10;
+        let mut lines: Vec<Vec<String>> = Vec::new();
+        let mut tokens: Vec<String> = Vec::new();
+        let mut length = overhead;
+        for token in self.isupport() {
+            if !tokens.is_empty()
+                && (tokens.len() == TOKENS_PER_LINE || length + token.len() + 1 > BUFFER_SIZE)
+            {
+                lines.push(tokens);
+                tokens = Vec::new();
+                length = overhead;
+            }
+            length += token.len() + 1;
+            tokens.push(token);
+        }
+        if !tokens.is_empty() {
+            lines.push(tokens);
+        }
+
+        for tokens in lines {
+            let mut parameters: Vec<&str> = tokens.iter().map(|token| token.as_str()).collect();
+            parameters.push(text);
+            reply.add_message(self.numeric("005", target, &parameters));
+        }
+        reply
+    }
+}
+
+// ISUPPORT values escape characters that would otherwise end or split a token
+// as \xHH.
+fn escape_isupport_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            ' ' | '\\' | '=' => escaped.push_str(&format!("\\x{:02X}", c as u32)),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
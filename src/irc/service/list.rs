@@ -0,0 +1,352 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::channel::{is_channel, Channel};
+use crate::irc::mask::matches;
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::{now, Service};
+use std::collections::{HashMap, VecDeque};
+use std::mem::take;
+
+// Listing is a LIST that is still being sent. Only the channel names are
+// copied up front, each channel is looked up and filtered again as it is
+// reached.
+struct Listing {
+    busy: bool,
+    generation: u64,
+    names: VecDeque<String>,
+    query: Query,
+    started: bool,
+}
+
+// Listings holds the LIST still being sent to each session. Every LIST gets a
+// new generation so a batch for one that has since been replaced is dropped.
+pub(super) struct Listings {
+    generation: u64,
+    listings: HashMap<u64, Listing>,
+}
+
+impl Listings {
+    pub(super) fn new() -> Listings {
+        Listings {
+            generation: 0,
+            listings: HashMap::new(),
+        }
+    }
+}
+
+// Query holds the ELIST filters a LIST was given. Times are in seconds since
+// the Unix epoch and every filter that is set must match. The C and T filters
+// count minutes back from now, so C>n, created more than n minutes ago, sets
+// created_before to the time n minutes ago, and T>n likewise sets
+// topic_before.
+#[derive(Clone, Default)]
+struct Query {
+    created_after: Option<u64>,
+    created_before: Option<u64>,
+    excludes: Vec<String>,
+    masks: Vec<String>,
+    max_users: Option<usize>,
+    min_users: Option<usize>,
+    names: Vec<String>,
+    topic_after: Option<u64>,
+    topic_before: Option<u64>,
+}
+
+impl Query {
+    // Returns true if the channel passes every filter. Masks are expected to
+    // have been folded along with the channel name.
+    fn matches(&self, channel: &Channel, name: &str) -> bool {
+        let users = channel.members().len();
+        let topic = channel.topic_time();
+        self.min_users.is_none_or(|min| users > min)
+            && self.max_users.is_none_or(|max| users < max)
+            && self
+                .created_after
+                .is_none_or(|time| channel.created() > time)
+            && self
+                .created_before
+                .is_none_or(|time| channel.created() < time)
+            && self.topic_after.is_none_or(|time| topic > time)
+            && self
+                .topic_before
+                .is_none_or(|time| topic > 0 && topic < time)
+            && (self.masks.is_empty() || self.masks.iter().any(|mask| matches(mask, name)))
+            && !self.excludes.iter().any(|mask| matches(mask, name))
+    }
+
+    // Parse LIST's comma separated parameter, where each item is a channel, a
+    // mask, a negated !mask or one of the ELIST filters >n and <n on users,
+    // C>n and C<n on minutes since creation and T>n and T<n on minutes since
+    // the topic was set.
+    fn parse(parameter: &str, fold: impl Fn(&str) -> String) -> Query {
+        let mut query = Query::default();
+        let time = now();
+        let ago = |minutes: &str| match minutes.parse::<u64>() {
+            Ok(minutes) => Some(time.saturating_sub(minutes.saturating_mul(60))),
+            Err(_e) => None,
+        };
+
+        for item in parameter.split(',').filter(|item| !item.is_empty()) {
+            let upper = item.to_ascii_uppercase();
+            if let Some(users) = item.strip_prefix('>') {
+                query.min_users = users.parse().ok();
+            } else if let Some(users) = item.strip_prefix('<') {
+                query.max_users = users.parse().ok();
+            } else if let Some(minutes) = upper.strip_prefix("C>") {
+                query.created_before = ago(minutes);
+            } else if let Some(minutes) = upper.strip_prefix("C<") {
+                query.created_after = ago(minutes);
+            } else if let Some(minutes) = upper.strip_prefix("T>") {
+                query.topic_before = ago(minutes);
+            } else if let Some(minutes) = upper.strip_prefix("T<") {
+                query.topic_after = ago(minutes);
+            } else if let Some(mask) = item.strip_prefix('!') {
+                query.excludes.push(fold(mask));
+            } else if item.contains(['*', '?']) {
+                query.masks.push(fold(item));
+            } else if is_channel(item) {
+                query.names.push(item.to_string());
+            }
+        }
+        query
+    }
+}
+
+impl Service {
+    pub(super) fn reply_list(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, _prefix) = self.identity(id)?;
+
+        let casemapping = self.config.casemapping();
+        let query = match message.parameters().first() {
+            Some(parameter) => Query::parse(parameter, |mask| casemapping.fold(mask)),
+            None => Query::default(),
+        };
+        let names = if query.names.is_empty() {
+            match self.channels.read() {
+                Ok(channels) => channels
+                    .channels()
+                    .map(|channel| channel.name().clone())
+                    .collect(),
+                Err(_e) => return None,
+            }
+        } else {
+            query.names.iter().cloned().collect()
+        };
+
+        // A new LIST replaces one still being sent, which is ended early. The
+        // channels themselves follow once this reply has been queued.
+        if let Ok(mut lists) = self.lists.lock() {
+            lists.generation += 1;
+            let listing = Listing {
+                busy: false,
+                generation: lists.generation,
+                names,
+                query,
+                started: false,
+            };
+            if lists.listings.insert(id, listing).is_some() {
+                reply.add_message(self.numeric("323", &nick, &["End of /LIST"]));
+            }
+        }
+        reply.add_message(self.numeric("321", &nick, &["Channel", "Users  Name"]));
+        Some(reply)
+    }
+
+    // Send as many of the channels left in a session's LIST as fit in half of
+    // its SendQ, so a long LIST never gets the client disconnected. The rest
+    // is sent as the client's queue drains.
+    pub(super) fn resume_list(&self, id: u64) {
+        let session = match self.session(id) {
+            Some(session) => session,
+            None => {
+                if let Ok(mut lists) = self.lists.lock() {
+                    lists.listings.remove(&id);
+                }
+                return;
+            }
+        };
+
+        // The listing stays in place, marked busy, while its channels are
+        // looked up so the Timer and the Worker never send it at once.
+        let (generation, mut names, query) = match self.lists.lock() {
+            Ok(mut lists) => match lists.listings.get_mut(&id) {
+                Some(listing) if !listing.busy => {
+                    listing.busy = true;
+                    listing.started = true;
+                    (
+                        listing.generation,
+                        take(&mut listing.names),
+                        listing.query.clone(),
+                    )
+                }
+                _ => return,
+            },
+            Err(_e) => return,
+        };
+
+        let (nick, queued) = match session.lock() {
+            Ok(session) => (session.nick().clone(), session.queued()),
+            Err(_e) => return,
+        };
+        let room = (self.config.sendq() / 2).saturating_sub(queued);
+
+        let mut reply = Reply::new();
+        let mut length = 0;
+        if let Ok(channels) = self.channels.read() {
+            let casemapping = self.config.casemapping();
+            while length < room {
+                let name = match names.pop_front() {
+                    Some(name) => name,
+                    None => break,
+                };
+                let channel = match channels.get(&name) {
+                    Some(channel) => channel,
+                    None => continue,
+                };
+                let hidden = channel.has_mode('s') || channel.has_mode('p');
+                if hidden && !channel.has_member(id) {
+                    continue;
+                }
+                if !query.matches(channel, &casemapping.fold(channel.name())) {
+                    continue;
+                }
+
+                // :<server> 322 <nick> <channel> <users> :<topic>\r\n
+                let users = channel.members().len().to_string();
+                length += self.name.len()
+                    + nick.len()
+                    + channel.name().len()
+                    + users.len()
+                    + channel.topic().len()
+                    + 12;
+                reply.add_message(self.numeric(
+                    "322",
+                    &nick,
+                    &[channel.name(), &users, channel.topic()],
+                ));
+            }
+        }
+
+        // A newer LIST has replaced this one, so what was found is stale. The
+        // lock is held while queueing so the newer LIST's replies cannot come
+        // before this batch.
+        let mut lists = match self.lists.lock() {
+            Ok(lists) => lists,
+            Err(_e) => return,
+        };
+        match lists.listings.get_mut(&id) {
+            Some(listing) if listing.generation == generation => {
+                if names.is_empty() {
+                    lists.listings.remove(&id);
+                    reply.add_message(self.numeric("323", &nick, &["End of /LIST"]));
+                } else {
+                    listing.names = names;
+                    listing.busy = false;
+                }
+            }
+            _ => return,
+        }
+        self.queue(&session, &reply);
+    }
+
+    // Continue every LIST that has already started sending.
+    pub fn resume_lists(&self) {
+        let ids: Vec<u64> = match self.lists.lock() {
+            Ok(lists) => lists
+                .listings
+                .iter()
+                .filter(|(_id, listing)| listing.started && !listing.busy)
+                .map(|(id, _listing)| *id)
+                .collect(),
+            Err(_e) => return,
+        };
+        for id in ids {
+            self.resume_list(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(parameter: &str) -> Query {
+        Query::parse(parameter, |mask| mask.to_lowercase())
+    }
+
+    // Assert time is n minutes before the present, allowing for the clock
+    // ticking over during the test.
+    fn assert_ago(time: Option<u64>, minutes: u64) {
+        let time = time.unwrap();
+        let expected = now() - minutes * 60;
+        assert!(time <= expected && time + 2 >= expected, "{:}", time);
+    }
+
+    #[test]
+    fn users() {
+        let query = parse(">5,<10");
+        assert_eq!(query.min_users, Some(5));
+        assert_eq!(query.max_users, Some(10));
+    }
+
+    #[test]
+    fn created() {
+        let query = parse("C>60,c<5");
+        assert_ago(query.created_before, 60);
+        assert_ago(query.created_after, 5);
+    }
+
+    #[test]
+    fn topics() {
+        let query = parse("T>30,t<2");
+        assert_ago(query.topic_before, 30);
+        assert_ago(query.topic_after, 2);
+        assert_eq!(query.created_before, None);
+        assert_eq!(query.created_after, None);
+    }
+
+    #[test]
+    fn masks() {
+        let query = parse("#A*,!#B?,#Chan,&Local,!#Excluded,nochannel");
+        assert_eq!(query.masks, vec!["#a*"]);
+        assert_eq!(query.excludes, vec!["#b?", "#excluded"]);
+        assert_eq!(query.names, vec!["#Chan", "&Local"]);
+    }
+
+    #[test]
+    fn unparseable_numbers() {
+        let query = parse(">,<x,C>-1,C<1.5,T>,T<abc,>99999999999999999999999");
+        assert_eq!(query.min_users, None);
+        assert_eq!(query.max_users, None);
+        assert_eq!(query.created_before, None);
+        assert_eq!(query.created_after, None);
+        assert_eq!(query.topic_before, None);
+        assert_eq!(query.topic_after, None);
+        assert!(query.masks.is_empty());
+        assert!(query.excludes.is_empty());
+        assert!(query.names.is_empty());
+    }
+
+    #[test]
+    fn empty_items() {
+        let query = parse(",,#a,,");
+        assert_eq!(query.names, vec!["#a"]);
+        assert_eq!(query.min_users, None);
+    }
+}
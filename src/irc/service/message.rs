@@ -0,0 +1,166 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::channel::is_channel;
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::{truncate, Service};
+use crate::irc::{BUFFER_SIZE, CLIENT_TAG_SIZE};
+
+impl Service {
+    // Deliver a PRIVMSG, NOTICE or TAGMSG to each of its comma separated
+    // targets and return any error replies for the sender. Client-only tags
+    // travel with the message.
+    fn deliver(&self, id: u64, message: &Message, command: &str) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, prefix) = self.identity(id)?;
+        let account = match self.session(id)?.lock() {
+            Ok(session) => session.account().clone(),
+            Err(_e) => return None,
+        };
+
+        let targets = match message.parameters().first() {
+            Some(targets) if !targets.is_empty() => targets,
+            _ => {
+                let text = format!("No recipient given ({:})", command);
+                reply.add_message(self.numeric("411", &nick, &[&text]));
+                return Some(reply);
+            }
+        };
+        let text = match message.parameters().get(1) {
+            Some(text) if !text.is_empty() => Some(text.as_str()),
+            _ if command == "TAGMSG" => None,
+            _ => {
+                reply.add_message(self.numeric("412", &nick, &["No text to send"]));
+                return Some(reply);
+            }
+        };
+
+        // The tag section is measured without its @ and trailing space.
+        let mut tagged = Message::new();
+        for (key, value) in message.client_tags() {
+            tagged.set_tag(&key, &value);
+        }
+        if tagged.tags_string().len().saturating_sub(2) > CLIENT_TAG_SIZE {
+            reply.add_message(self.numeric("417", &nick, &["Input line was too long"]));
+            return Some(reply);
+        }
+
+        for (i, target) in targets.split(',').enumerate() {
+            if i >= self.config.max_targets() {
+                reply.add_message(self.numeric("407", &nick, &[target, "Too many recipients"]));
+                continue;
+            }
+
+            // The sender's line fit, but relaying adds its prefix, so trim the
+            // text until the relayed line does too.
+            // :<prefix> <command> <target> :<text>\r\n
+            let mut relayed = match text {
+                Some(text) => {
+                    let overhead = prefix.len() + command.len() + target.len() + 7;
+                    let text = truncate(text, BUFFER_SIZE.saturating_sub(overhead));
+                    Message::from_parts(&prefix, command, &[target, text])
+                }
+                None => Message::from_parts(&prefix, command, &[target]),
+            };
+            for (key, value) in tagged.tags() {
+                relayed.set_tag(key, value);
+            }
+            let mut relay = Reply::new();
+            relay.add_message(relayed);
+
+            if is_channel(target) {
+                let allowed = match self.channels.read() {
+                    Ok(channels) => channels
+                        .get(target)
+                        .map(|channel| self.can_send(channel, id, &prefix)),
+                    Err(_e) => return None,
+                };
+                match allowed {
+                    Some(true) => self.broadcast(target, &relay, Some(id)),
+                    Some(false) => {
+                        reply.add_message(self.numeric(
+                            "404",
+                            &nick,
+                            &[target, "Cannot send to channel"],
+                        ));
+                    }
+                    None => {
+                        reply.add_message(self.numeric("403", &nick, &[target, "No such channel"]));
+                    }
+                }
+            } else {
+                let recipient = match self.sessions.read() {
+                    Ok(sessions) => sessions.find(target).and_then(|session| {
+                        let session = session.lock().ok()?;
+                        Some((
+                            session.id(),
+                            session.nick().clone(),
+                            session.has_mode('R'),
+                            session.away().cloned(),
+                        ))
+                    }),
+                    Err(_e) => return None,
+                };
+                match recipient {
+                    // Clients with +R only hear from users logged in to an
+                    // account.
+                    Some((_recipient, _nick, true, _away)) if account.is_empty() => {
+                        reply.add_message(self.numeric(
+                            "486",
+                            &nick,
+                            &[target, "You must log in to an account to message this user"],
+                        ));
+                    }
+                    // PRIVMSG to someone away gets their away message back.
+                    Some((recipient, recipient_nick, _registered_only, away)) => {
+                        self.send(recipient, &relay);
+                        if let (Some(away), "PRIVMSG") = (away, command) {
+                            reply.add_message(self.numeric(
+                                "301",
+                                &nick,
+                                &[&recipient_nick, &away],
+                            ));
+                        }
+                    }
+                    None => {
+                        reply.add_message(self.numeric(
+                            "401",
+                            &nick,
+                            &[target, "No such nick/channel"],
+                        ));
+                    }
+                }
+            }
+        }
+
+        Some(reply)
+    }
+
+    // NOTICE never generates automatic replies, so errors are discarded.
+    pub(super) fn reply_notice(&self, id: u64, message: &Message) -> Option<Reply> {
+        self.deliver(id, message, "NOTICE");
+        None
+    }
+
+    pub(super) fn reply_privmsg(&self, id: u64, message: &Message) -> Option<Reply> {
+        self.deliver(id, message, "PRIVMSG")
+    }
+
+    pub(super) fn reply_tagmsg(&self, id: u64, message: &Message) -> Option<Reply> {
+        self.deliver(id, message, "TAGMSG")
+    }
+}
@@ -0,0 +1,496 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::channel::{
+    is_channel, Channel, ListEntry, FLAG_MODES, KEY_MODES, LIMIT_MODES, LIST_MODES, PREFIXES,
+};
+use crate::irc::mask::{matches, normalize};
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::{now, Service, USER_MODES};
+use crate::irc::BUFFER_SIZE;
+
+// A single mode change: whether it is being set, the mode and its parameter.
+type Change = (bool, char, Option<String>);
+
+impl Service {
+    // Returns true when prefix is banned from a channel and not exempt.
+    pub(super) fn banned(&self, channel: &Channel, prefix: &str) -> bool {
+        self.listed(channel, 'b', prefix) && !self.listed(channel, 'e', prefix)
+    }
+
+    // Returns true when a session may send messages to a channel.
+    pub(super) fn can_send(&self, channel: &Channel, id: u64, prefix: &str) -> bool {
+        if !channel.has_member(id) && channel.has_mode('n') {
+            return false;
+        }
+        // Voiced members and above are exempt from moderation and bans.
+        if channel.rank(id) >= rank('v') {
+            return true;
+        }
+        !channel.has_mode('m') && !self.banned(channel, prefix)
+    }
+
+    // Build the 324 and 329 replies describing a channel's modes.
+    fn channel_modes(&self, id: u64, nick: &str, name: &str) -> Reply {
+        let mut reply = Reply::new();
+        let channels = match self.channels.read() {
+            Ok(channels) => channels,
+            Err(_e) => return reply,
+        };
+        let channel = match channels.get(name) {
+            Some(channel) => channel,
+            None => {
+                reply.add_message(self.numeric("403", nick, &[name, "No such channel"]));
+                return reply;
+            }
+        };
+        let name = channel.name().clone();
+
+        // Only members get to see the key.
+        let mut modes = format!("+{:}", channel.modes());
+        let mut parameters = vec![name.clone()];
+        if !channel.key().is_empty() {
+            modes.push('k');
+            if channel.has_member(id) {
+                parameters.push(channel.key().clone());
+            } else {
+                parameters.push("*".to_string());
+            }
+        }
+        if channel.limit() > 0 {
+            modes.push('l');
+            parameters.push(channel.limit().to_string());
+        }
+        parameters.insert(1, modes);
+
+        let parameters: Vec<&str> = parameters.iter().map(|p| p.as_str()).collect();
+        reply.add_message(self.numeric("324", nick, &parameters));
+        reply.add_message(self.numeric("329", nick, &[&name, &channel.created().to_string()]));
+        reply
+    }
+
+    // Returns true when prefix matches any mask on one of a channel's lists.
+    pub(super) fn listed(&self, channel: &Channel, list: char, prefix: &str) -> bool {
+        let casemapping = self.config.casemapping();
+        let prefix = casemapping.fold(prefix);
+        channel
+            .entries(list)
+            .iter()
+            .any(|entry| matches(&casemapping.fold(entry.mask()), &prefix))
+    }
+
+    // Build the replies listing every mask on one of a channel's lists.
+    fn list_entries(&self, nick: &str, channel: &Channel, list: char) -> Reply {
+        let mut reply = Reply::new();
+        let (entry, end, text) = match list {
+            'b' => ("367", "368", "End of channel ban list"),
+            'e' => ("348", "349", "End of channel exception list"),
+            _ => ("346", "347", "End of channel invite list"),
+        };
+        for e in channel.entries(list) {
+            reply.add_message(self.numeric(
+                entry,
+                nick,
+                &[channel.name(), e.mask(), e.setter(), &e.time().to_string()],
+            ));
+        }
+        reply.add_message(self.numeric(end, nick, &[channel.name(), text]));
+        reply
+    }
+
+    // Build MODE lines announcing changes to a channel, each carrying at most
+    // MODES parameters and fitting within BUFFER_SIZE.
+    fn mode_lines(&self, prefix: &str, name: &str, changes: &[Change]) -> Reply {
+        let mut reply = Reply::new();
+        let mut lines: Vec<(String, Vec<String>)> = Vec::new();
+
+        // :<prefix> MODE <channel> <modes> <parameters>\r\n
+        let overhead = prefix.len() + name.len() + 10;
+        let mut modes = String::new();
+        let mut parameters: Vec<String> = Vec::new();
+        let mut length = overhead;
+        let mut sign = None;
+        for (adding, mode, argument) in changes {
+            let extra = match argument {
+                Some(argument) => argument.len() + 3,
+                None => 2,
+            };
+            let full = argument.is_some() && parameters.len() == self.config.modes();
+            if !modes.is_empty() && (full || length + extra > BUFFER_SIZE) {
+                lines.push((modes, parameters));
+                modes = String::new();
+                parameters = Vec::new();
+                length = overhead;
+                sign = None;
+            }
+            if sign != Some(*adding) {
+                modes.push(if *adding { '+' } else { '-' });
+                sign = Some(*adding);
+            }
+            modes.push(*mode);
+            length += extra;
+            if let Some(argument) = argument {
+                parameters.push(argument.clone());
+            }
+        }
+        if !modes.is_empty() {
+            lines.push((modes, parameters));
+        }
+
+        for (modes, parameters) in lines {
+            let mut line: Vec<&str> = vec![name, &modes];
+            line.extend(parameters.iter().map(|p| p.as_str()));
+            reply.add_message(Message::from_parts(prefix, "MODE", &line));
+        }
+        reply
+    }
+
+    // Returns the prefix modes channels support and their symbols, from the
+    // highest rank to the lowest.
+    pub(super) fn prefixes(&self) -> Vec<(char, char)> {
+        PREFIXES
+            .iter()
+            .filter(|(mode, _symbol)| self.config.prefix_modes().contains(*mode))
+            .cloned()
+            .collect()
+    }
+
+    fn reply_channel_mode(
+        &self,
+        id: u64,
+        nick: &str,
+        prefix: &str,
+        message: &Message,
+    ) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let target = message.parameters().first()?;
+
+        // MODE <channel> shows the channel's modes.
+        let modestring = match message.parameters().get(1) {
+            Some(modestring) => modestring,
+            None => return Some(self.channel_modes(id, nick, target)),
+        };
+
+        // Work out the requested changes, limited to MODES of them taking a
+        // parameter. List modes without a mask ask for the list instead.
+        let prefixes = self.prefixes();
+        let prefix_mode = |mode: char| prefixes.iter().any(|(m, _symbol)| *m == mode);
+        let mut arguments = message.parameters().iter().skip(2);
+        let mut adding = true;
+        let mut changes: Vec<Change> = Vec::new();
+        let mut queries: Vec<char> = Vec::new();
+        let mut parameters = 0;
+        for mode in modestring.chars() {
+            let takes_argument = match mode {
+                '+' => {
+                    adding = true;
+                    continue;
+                }
+                '-' => {
+                    adding = false;
+                    continue;
+                }
+                _ if prefix_mode(mode) || LIST_MODES.contains(mode) => true,
+                _ if KEY_MODES.contains(mode) => true,
+                _ if LIMIT_MODES.contains(mode) => adding,
+                _ if FLAG_MODES.contains(mode) => false,
+                _ => {
+                    let mode = mode.to_string();
+                    reply.add_message(self.numeric(
+                        "472",
+                        nick,
+                        &[&mode, "is unknown mode char to me"],
+                    ));
+                    continue;
+                }
+            };
+
+            let argument = if takes_argument {
+                arguments.next().cloned()
+            } else {
+                None
+            };
+            match argument {
+                Some(_) => {
+                    if parameters == self.config.modes() {
+                        continue;
+                    }
+                    parameters += 1;
+                }
+                None if LIST_MODES.contains(mode) => {
+                    if !queries.contains(&mode) {
+                        queries.push(mode);
+                    }
+                    continue;
+                }
+                // Removing a key does not need the key itself.
+                None if takes_argument && (adding || !KEY_MODES.contains(mode)) => continue,
+                None => {}
+            }
+            changes.push((adding, mode, argument));
+        }
+
+        // Look up the members named by prefix modes before taking the
+        // channels lock.
+        let owners: Vec<Option<(u64, String)>> = match self.sessions.read() {
+            Ok(sessions) => changes
+                .iter()
+                .map(|(_adding, mode, argument)| match argument {
+                    Some(argument) if prefix_mode(*mode) => {
+                        let id = sessions.owner(argument)?;
+                        let nick = sessions.get(id)?.lock().ok()?.nick().clone();
+                        Some((id, nick))
+                    }
+                    _ => None,
+                })
+                .collect(),
+            Err(_e) => return None,
+        };
+
+        let mut channels = match self.channels.write() {
+            Ok(channels) => channels,
+            Err(_e) => return None,
+        };
+        let channel = match channels.get_mut(target) {
+            Some(channel) => channel,
+            None => {
+                reply.add_message(self.numeric("403", nick, &[target, "No such channel"]));
+                return Some(reply);
+            }
+        };
+        let name = channel.name().clone();
+        let rank = channel.rank(id);
+
+        let mut applied: Vec<Change> = Vec::new();
+        let mut denied = false;
+        for ((adding, mode, argument), owner) in changes.into_iter().zip(owners) {
+            if prefix_mode(mode) {
+                let (member, member_nick) = match owner {
+                    Some(owner) => owner,
+                    None => {
+                        let argument = argument.unwrap_or_default();
+                        reply.add_message(self.numeric(
+                            "401",
+                            nick,
+                            &[&argument, "No such nick/channel"],
+                        ));
+                        continue;
+                    }
+                };
+                // Members may always give up their own status.
+                if rank < required_rank(mode) && (adding || member != id) {
+                    denied = true;
+                    continue;
+                }
+                if !channel.has_member(member) {
+                    reply.add_message(self.numeric(
+                        "441",
+                        nick,
+                        &[&member_nick, &name, "They aren't on that channel"],
+                    ));
+                    continue;
+                }
+                if channel.has_status(member, mode) == adding {
+                    continue;
+                }
+                if adding {
+                    channel.add_status(member, mode);
+                } else {
+                    channel.remove_status(member, mode);
+                }
+                applied.push((adding, mode, Some(member_nick)));
+                continue;
+            }
+
+            if rank < required_rank(mode) {
+                denied = true;
+                continue;
+            }
+
+            if LIST_MODES.contains(mode) {
+                let mask = normalize(&argument.unwrap_or_default());
+                if !adding {
+                    if let Some(mask) = channel.remove_entry(mode, &mask) {
+                        applied.push((false, mode, Some(mask)));
+                    }
+                } else if channel.entries(mode).len() >= self.config.max_list() {
+                    let list = mode.to_string();
+                    reply.add_message(self.numeric(
+                        "478",
+                        nick,
+                        &[&name, &list, "Channel list is full"],
+                    ));
+                } else if channel.add_entry(mode, ListEntry::new(&mask, prefix, now())) {
+                    applied.push((true, mode, Some(mask)));
+                }
+            } else if KEY_MODES.contains(mode) {
+                if adding {
+                    let key = argument.unwrap_or_default();
+                    if key.is_empty() || key.contains(',') || &key == channel.key() {
+                        continue;
+                    }
+                    channel.set_key(&key);
+                    applied.push((true, mode, Some(key)));
+                } else if !channel.key().is_empty() {
+                    channel.set_key("");
+                    applied.push((false, mode, Some("*".to_string())));
+                }
+            } else if LIMIT_MODES.contains(mode) {
+                if adding {
+                    let limit = match argument.unwrap_or_default().parse::<usize>() {
+                        Ok(limit) if limit > 0 => limit,
+                        _ => continue,
+                    };
+                    if limit == channel.limit() {
+                        continue;
+                    }
+                    channel.set_limit(limit);
+                    applied.push((true, mode, Some(limit.to_string())));
+                } else if channel.limit() > 0 {
+                    channel.set_limit(0);
+                    applied.push((false, mode, None));
+                }
+            } else if channel.has_mode(mode) != adding {
+                if adding {
+                    channel.add_mode(mode);
+                } else {
+                    channel.remove_mode(mode);
+                }
+                applied.push((adding, mode, None));
+            }
+        }
+
+        for list in queries {
+            reply = reply + self.list_entries(nick, channel, list);
+        }
+        if denied {
+            reply.add_message(self.numeric("482", nick, &[&name, "You're not channel operator"]));
+        }
+        drop(channels);
+
+        // Everyone in the channel sees what changed, the sender in order with
+        // the rest of its replies.
+        if !applied.is_empty() {
+            let lines = self.mode_lines(prefix, &name, &applied);
+            self.broadcast(&name, &lines, Some(id));
+            reply = reply + lines;
+        }
+        Some(reply)
+    }
+
+    pub(super) fn reply_mode(&self, id: u64, message: &Message) -> Option<Reply> {
+        let (nick, prefix) = self.identity(id)?;
+
+        match message.parameters().first() {
+            Some(target) if is_channel(target) => {
+                self.reply_channel_mode(id, &nick, &prefix, message)
+            }
+            Some(target) if !target.is_empty() => self.reply_user_mode(id, &nick, message),
+            _ => {
+                let mut reply = Reply::new();
+                reply.add_message(self.numeric("461", &nick, &["MODE", "Not enough parameters"]));
+                Some(reply)
+            }
+        }
+    }
+
+    fn reply_user_mode(&self, id: u64, nick: &str, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let target = message.parameters().first()?;
+
+        let casemapping = self.config.casemapping();
+        if casemapping.fold(target) != casemapping.fold(nick) {
+            reply.add_message(self.numeric("502", nick, &["Can't change mode for other users"]));
+            return Some(reply);
+        }
+
+        let session = self.session(id)?;
+        let mut session = match session.lock() {
+            Ok(session) => session,
+            Err(_e) => return None,
+        };
+
+        // MODE <nick> shows the client's modes.
+        let modestring = match message.parameters().get(1) {
+            Some(modestring) => modestring,
+            None => {
+                let modes = format!("+{:}", session.modes());
+                reply.add_message(self.numeric("221", nick, &[&modes]));
+                return Some(reply);
+            }
+        };
+
+        let mut adding = true;
+        let mut applied: Vec<Change> = Vec::new();
+        let mut unknown = false;
+        for mode in modestring.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ if !USER_MODES.contains(mode) => unknown = true,
+                _ if session.has_mode(mode) == adding => {}
+                // Operator status is only given by OPER, and server notices
+                // are only for operators.
+                'o' if adding => {}
+                's' if adding && !session.has_mode('o') => {}
+                _ => {
+                    if adding {
+                        session.add_mode(mode);
+                    } else {
+                        session.remove_mode(mode);
+                    }
+                    applied.push((adding, mode, None));
+                }
+            }
+        }
+
+        // Operators lose server notices along with their status.
+        if !session.has_mode('o') && session.has_mode('s') {
+            session.remove_mode('s');
+            applied.push((false, 's', None));
+        }
+
+        if unknown {
+            reply.add_message(self.numeric("501", nick, &["Unknown MODE flag"]));
+        }
+        if !applied.is_empty() {
+            let prefix = session.prefix();
+            reply = reply + self.mode_lines(&prefix, nick, &applied);
+        }
+        Some(reply)
+    }
+}
+
+// Returns the rank given by a prefix mode, matching Channel::rank.
+pub(super) fn rank(mode: char) -> usize {
+    match PREFIXES.iter().position(|(m, _symbol)| *m == mode) {
+        Some(i) => PREFIXES.len() - i,
+        None => 0,
+    }
+}
+
+// Returns the rank needed to change a mode. Half operators may give voice and
+// change the channel's other modes, operators may also give operator and half
+// operator, and admin and owner may only be given by their equals.
+fn required_rank(mode: char) -> usize {
+    match mode {
+        'v' => rank('h'),
+        'h' | 'o' => rank('o'),
+        'a' | 'q' => rank(mode),
+        _ => rank('h'),
+    }
+}
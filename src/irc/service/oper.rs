@@ -0,0 +1,153 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::Service;
+
+impl Service {
+    // Tell operators with +s that a client has finished registering.
+    pub(super) fn notice_connecting(&self, id: u64) {
+        let text = match self.session(id) {
+            Some(session) => match session.lock() {
+                Ok(session) => format!(
+                    "Client connecting: {:} ({:}@{:}) [{:}]",
+                    session.nick(),
+                    session.user(),
+                    session.hostname(),
+                    session.realname()
+                ),
+                Err(_e) => return,
+            },
+            None => return,
+        };
+        self.server_notice(&text);
+    }
+
+    pub(super) fn reply_oper(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let session = self.session(id)?;
+        let mut session = match session.lock() {
+            Ok(session) => session,
+            Err(_e) => return None,
+        };
+        let nick = session.nick().clone();
+
+        // OPER <name> <password>
+        let parameters = message.parameters();
+        if parameters.len() < 2 {
+            reply.add_message(self.numeric("461", &nick, &["OPER", "Not enough parameters"]));
+            return Some(reply);
+        }
+
+        match self.config.operator(&parameters[0]) {
+            Some(password) if *password == parameters[1] => {}
+            Some(_password) => {
+                reply.add_message(self.numeric("464", &nick, &["Password incorrect"]));
+                return Some(reply);
+            }
+            None => {
+                reply.add_message(self.numeric("491", &nick, &["No O-lines for your host"]));
+                return Some(reply);
+            }
+        }
+
+        reply.add_message(self.numeric("381", &nick, &["You are now an IRC operator"]));
+        if !session.has_mode('o') {
+            session.add_mode('o');
+            reply.add_message(Message::from_parts(
+                &session.prefix(),
+                "MODE",
+                &[&nick, "+o"],
+            ));
+        }
+        Some(reply)
+    }
+
+    // WALLOPS sends a message from an operator to every client with +w.
+    pub(super) fn reply_wallops(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (prefix, nick, operator) = match self.session(id)?.lock() {
+            Ok(session) => (
+                session.prefix(),
+                session.nick().clone(),
+                session.has_mode('o'),
+            ),
+            Err(_e) => return None,
+        };
+
+        let text = match message.parameters().first() {
+            Some(text) if !text.is_empty() => text,
+            _ => {
+                reply.add_message(self.numeric(
+                    "461",
+                    &nick,
+                    &["WALLOPS", "Not enough parameters"],
+                ));
+                return Some(reply);
+            }
+        };
+        if !operator {
+            reply.add_message(self.numeric(
+                "481",
+                &nick,
+                &["Permission Denied- You're not an IRC operator"],
+            ));
+            return Some(reply);
+        }
+
+        let mut wallops = Reply::new();
+        wallops.add_message(Message::from_parts(&prefix, "WALLOPS", &[text]));
+        for member in self.with_mode('w') {
+            self.send(member, &wallops);
+        }
+        None
+    }
+
+    // Send a server notice to every client with +s.
+    pub(super) fn server_notice(&self, text: &str) {
+        let text = format!("*** Notice -- {:}", text);
+        for id in self.with_mode('s') {
+            let nick = match self.session(id) {
+                Some(session) => match session.lock() {
+                    Ok(session) => session.nick().clone(),
+                    Err(_e) => continue,
+                },
+                None => continue,
+            };
+            let mut reply = Reply::new();
+            reply.add_message(Message::from_parts(&self.name, "NOTICE", &[&nick, &text]));
+            self.send(id, &reply);
+        }
+    }
+
+    // Returns the ID of every registered session with a user mode set.
+    fn with_mode(&self, mode: char) -> Vec<u64> {
+        let mut ids = Vec::new();
+        if let Ok(sessions) = self.sessions.read() {
+            for id in sessions.ids() {
+                if let Some(session) = sessions.get(id) {
+                    if let Ok(session) = session.lock() {
+                        if session.registered() && session.has_mode(mode) {
+                            ids.push(id);
+                        }
+                    }
+                }
+            }
+        }
+        ids
+    }
+}
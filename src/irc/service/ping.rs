@@ -0,0 +1,112 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::Service;
+use std::time::{Duration, Instant};
+
+// How long a closed connection has to finish writing its final ERROR before
+// it is shut down regardless.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Service {
+    // Disconnect clients that never finished registering or stopped answering
+    // PINGs, and PING registered clients that have gone quiet. Called
+    // periodically by the Timer.
+    pub fn check_timeouts(&self) {
+        let ids = match self.sessions.write() {
+            Ok(mut sessions) => {
+                sessions.expire_closing(CLOSE_TIMEOUT);
+                sessions.ids()
+            }
+            Err(_e) => return,
+        };
+
+        for id in ids {
+            let session = match self.session(id) {
+                Some(session) => session,
+                None => continue,
+            };
+            let mut session = match session.lock() {
+                Ok(session) => session,
+                Err(_e) => continue,
+            };
+            // Closed connections are quit once the Listener sees them shut
+            // down, which happens when their ERROR has been written or they
+            // have taken too long to read it.
+            if session.closed() {
+                if let Some(closed_time) = session.closed_time() {
+                    if closed_time.elapsed() > CLOSE_TIMEOUT {
+                        session.shutdown();
+                    }
+                }
+                continue;
+            }
+
+            if !session.registered() {
+                if session.connected().elapsed() > self.config.registration_timeout() {
+                    drop(session);
+                    self.quit(id, "Registration timed out");
+                }
+                continue;
+            }
+
+            match session.ping_sent() {
+                Some(ping_sent) => {
+                    if ping_sent.elapsed() > self.config.ping_timeout() {
+                        drop(session);
+                        self.quit(id, "Ping timeout");
+                    }
+                }
+                None => {
+                    if session.last_seen().elapsed() > self.config.ping_frequency() {
+                        session.set_ping_sent(Some(Instant::now()));
+                        drop(session);
+
+                        let mut reply = Reply::new();
+                        reply.add_message(Message::from_parts(&self.name, "PING", &[&self.name]));
+                        self.send(id, &reply);
+                    }
+                }
+            }
+        }
+    }
+
+    pub(super) fn reply_ping(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        match message.parameters().first() {
+            Some(token) => {
+                reply.add_message(Message::from_parts(
+                    &self.name,
+                    "PONG",
+                    &[&self.name, token],
+                ));
+            }
+            None => {
+                let target = self.session(id)?.lock().ok()?.target();
+                reply.add_message(self.numeric("409", &target, &["No origin specified"]));
+            }
+        }
+        Some(reply)
+    }
+
+    // Receiving anything from a client already counts as an answer to our
+    // PING, so there is nothing left to do for a PONG.
+    pub(super) fn reply_pong(&self, _id: u64, _message: &Message) -> Option<Reply> {
+        None
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::Service;
+
+impl Service {
+    // End a session, telling everyone who shares a channel with it why it
+    // left. Sessions can be quit from several places at once, such as a QUIT
+    // racing a ping timeout, but only the caller that removes the session from
+    // the registry goes on to announce it.
+    pub(super) fn quit(&self, id: u64, reason: &str) {
+        let session = match self.sessions.write() {
+            Ok(mut sessions) => sessions.remove(id),
+            Err(_e) => return,
+        };
+        let session = match session {
+            Some(session) => session,
+            None => return,
+        };
+
+        // A connection that was already closed, by a write error or a full
+        // SendQ, keeps the reason it was closed with.
+        let (prefix, joined, reason, registered, queued) = match session.lock() {
+            Ok(mut session) => {
+                let reason = match session.closed_reason() {
+                    Some(closed_reason) => closed_reason.clone(),
+                    None => reason.to_string(),
+                };
+                session.close(&reason);
+                if session.registered() {
+                    self.remember(&session);
+                }
+                let joined: Vec<String> = session.channels().iter().cloned().collect();
+                let queued = session.queued() > 0;
+                (
+                    session.prefix(),
+                    joined,
+                    reason,
+                    session.registered(),
+                    queued,
+                )
+            }
+            Err(_e) => return,
+        };
+
+        // Anything still queued, such as the final ERROR, is written as the
+        // connection drains.
+        if queued {
+            if let Ok(mut sessions) = self.sessions.write() {
+                sessions.add_closing(id, session);
+            }
+        }
+
+        // Leave every channel after finding who to notify.
+        let members = self.neighbours(id, &joined);
+        if let Ok(mut channels) = self.channels.write() {
+            for name in joined {
+                channels.part(&name, id);
+            }
+        }
+
+        let mut reply = Reply::new();
+        reply.add_message(Message::from_parts(&prefix, "QUIT", &[&reason]));
+        for member in members {
+            self.send(member, &reply);
+        }
+
+        if registered {
+            self.server_notice(&format!("Client exiting: {:} [{:}]", prefix, reason));
+        }
+    }
+
+    pub(super) fn reply_quit(&self, id: u64, message: &Message) {
+        let reason = match message.parameters().first() {
+            Some(text) if !text.is_empty() => format!("Quit: {:}", text),
+            _ => "Client Quit".to_string(),
+        };
+        self.quit(id, &reason);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::channel::is_channel;
+use crate::irc::mask::matches;
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::Service;
+
+// The WHOX fields in the order they are always sent, whatever order they were
+// asked for in.
+const WHOX_FIELDS: &str = "tcuihsnfdlaor";
+
+impl Service {
+    pub(super) fn reply_who(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, operator, all, joined) = match self.session(id)?.lock() {
+            Ok(session) => (
+                session.nick().clone(),
+                session.has_mode('o'),
+                session.has_capability("multi-prefix"),
+                session.channels().clone(),
+            ),
+            Err(_e) => return None,
+        };
+
+        let mask = match message.parameters().first() {
+            Some(mask) if !mask.is_empty() && mask != "0" => mask.clone(),
+            _ => "*".to_string(),
+        };
+
+        // WHO <mask> [o][%<fields>[,<token>]]
+        let options = message.parameters().get(1).cloned().unwrap_or_default();
+        let (flags, whox) = match options.find('%') {
+            Some(i) => (&options[..i], Some(&options[i + 1..])),
+            None => (options.as_str(), None),
+        };
+        let operators_only = flags.contains('o');
+        let (fields, token) = match whox {
+            Some(whox) => match whox.find(',') {
+                Some(i) => (Some(&whox[..i]), &whox[i + 1..]),
+                None => (Some(whox), "0"),
+            },
+            None => (None, "0"),
+        };
+
+        // Find who to list along with the channel to show for each and their
+        // prefix in it. Invisible users are only listed to operators and
+        // those who share a channel with them.
+        let mut candidates: Vec<(u64, String, String, bool)> = Vec::new();
+        if is_channel(&mask) {
+            if let Ok(channels) = self.channels.read() {
+                if let Some(channel) = channels.get(&mask) {
+                    let member = channel.has_member(id);
+                    if member || operator || !channel.has_mode('s') {
+                        for m in channel.members() {
+                            let visible = member || operator;
+                            candidates.push((
+                                *m,
+                                channel.name().clone(),
+                                channel.prefix(*m, all),
+                                visible,
+                            ));
+                        }
+                    }
+                }
+            }
+        } else if let Ok(sessions) = self.sessions.read() {
+            for m in sessions.ids() {
+                candidates.push((m, "*".to_string(), String::new(), operator));
+            }
+        }
+
+        let casemapping = self.config.casemapping();
+        let folded = casemapping.fold(&mask);
+        if let Ok(sessions) = self.sessions.read() {
+            for (m, channel, prefix, visible) in candidates {
+                let session = match sessions.get(m) {
+                    Some(session) => session,
+                    None => continue,
+                };
+                let session = match session.lock() {
+                    Ok(session) => session,
+                    Err(_e) => continue,
+                };
+                if !session.registered() || (operators_only && !session.has_mode('o')) {
+                    continue;
+                }
+
+                if channel == "*" {
+                    let matched = [
+                        session.nick(),
+                        session.user(),
+                        session.hostname(),
+                        session.realname(),
+                        &self.name,
+                    ]
+                    .iter()
+                    .any(|field| matches(&folded, &casemapping.fold(field)));
+                    if !matched {
+                        continue;
+                    }
+                }
+
+                // Asking for a nick by name always finds it.
+                let shared = !session.channels().is_disjoint(&joined);
+                let exact = folded == casemapping.fold(session.nick());
+                if !visible && m != id && session.has_mode('i') && !shared && !exact {
+                    continue;
+                }
+
+                let mut status = match session.away() {
+                    Some(_away) => "G".to_string(),
+                    None => "H".to_string(),
+                };
+                if session.has_mode('o') {
+                    status.push('*');
+                }
+                if session.has_mode('B') {
+                    status.push('B');
+                }
+                status.push_str(&prefix);
+
+                let line = match fields {
+                    None => self.numeric(
+                        "352",
+                        &nick,
+                        &[
+                            &channel,
+                            session.user(),
+                            session.hostname(),
+                            &self.name,
+                            session.nick(),
+                            &status,
+                            &format!("0 {:}", session.realname()),
+                        ],
+                    ),
+                    Some(fields) => {
+                        // Only operators and the client itself see real IPs.
+                        let ip = if operator || m == id {
+                            session.connection().host()
+                        } else {
+                            "255.255.255.255".to_string()
+                        };
+                        let account = if session.account().is_empty() {
+                            "0".to_string()
+                        } else {
+                            session.account().clone()
+                        };
+                        let idle = session.last_active().elapsed().as_secs().to_string();
+
+                        let mut parameters: Vec<&str> = Vec::new();
+                        for field in WHOX_FIELDS.chars().filter(|f| fields.contains(*f)) {
+                            parameters.push(match field {
+                                't' => token,
+                                'c' => &channel,
+                                'u' => session.user(),
+                                'i' => &ip,
+                                'h' => session.hostname(),
+                                's' => &self.name,
+                                'n' => session.nick(),
+                                'f' => &status,
+                                'd' => "0",
+                                'l' => &idle,
+                                'a' => &account,
+                                'o' => "n/a",
+                                _ => session.realname(),
+                            });
+                        }
+                        self.numeric("354", &nick, &parameters)
+                    }
+                };
+                reply.add_message(line);
+            }
+        }
+
+        reply.add_message(self.numeric("315", &nick, &[&mask, "End of WHO list"]));
+        Some(reply)
+    }
+}
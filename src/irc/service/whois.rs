@@ -0,0 +1,210 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::message::{Message, Reply};
+use crate::irc::service::{format_time, Service};
+use crate::irc::BUFFER_SIZE;
+
+impl Service {
+    pub(super) fn reply_whois(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, operator, all) = match self.session(id)?.lock() {
+            Ok(session) => (
+                session.nick().clone(),
+                session.has_mode('o'),
+                session.has_capability("multi-prefix"),
+            ),
+            Err(_e) => return None,
+        };
+
+        // WHOIS [<server>] <nick>[,<nick>]
+        let targets = match message.parameters().len() {
+            0 => "",
+            1 => &message.parameters()[0],
+            _ => &message.parameters()[1],
+        };
+        if targets.is_empty() {
+            reply.add_message(self.numeric("431", &nick, &["No nickname given"]));
+            return Some(reply);
+        }
+
+        for target in targets.split(',').filter(|target| !target.is_empty()) {
+            reply = reply + self.whois(id, &nick, operator, all, target);
+        }
+        Some(reply)
+    }
+
+    pub(super) fn reply_whowas(&self, id: u64, message: &Message) -> Option<Reply> {
+        let mut reply = Reply::new();
+        let (nick, _prefix) = self.identity(id)?;
+
+        // WHOWAS <nick> [<count>]
+        let target = match message.parameters().first() {
+            Some(target) if !target.is_empty() => target,
+            _ => {
+                reply.add_message(self.numeric("431", &nick, &["No nickname given"]));
+                return Some(reply);
+            }
+        };
+        let count = match message.parameters().get(1) {
+            Some(count) => match count.parse::<usize>() {
+                Ok(count) if count > 0 => count,
+                _ => usize::MAX,
+            },
+            None => usize::MAX,
+        };
+
+        let mut found = false;
+        if let Ok(whowas) = self.whowas.lock() {
+            for entry in whowas.find(target).into_iter().take(count) {
+                found = true;
+                reply.add_message(self.numeric(
+                    "314",
+                    &nick,
+                    &[
+                        entry.nick(),
+                        entry.user(),
+                        entry.hostname(),
+                        "*",
+                        entry.realname(),
+                    ],
+                ));
+                reply.add_message(self.numeric(
+                    "312",
+                    &nick,
+                    &[entry.nick(), &self.name, &format_time(entry.time())],
+                ));
+            }
+        }
+        if !found {
+            reply.add_message(self.numeric("406", &nick, &[target, "There was no such nickname"]));
+        }
+        reply.add_message(self.numeric("369", &nick, &[target, "End of WHOWAS"]));
+        Some(reply)
+    }
+
+    // Build the WHOIS replies for a single nick. Secret and private channels
+    // are only listed to operators and their members.
+    fn whois(&self, id: u64, nick: &str, operator: bool, all: bool, target: &str) -> Reply {
+        let mut reply = Reply::new();
+
+        let session = match self.sessions.read() {
+            Ok(sessions) => sessions.find(target),
+            Err(_e) => None,
+        };
+        let session = match session {
+            Some(session) => session,
+            None => {
+                reply.add_message(self.numeric("401", nick, &[target, "No such nick/channel"]));
+                reply.add_message(self.numeric("318", nick, &[target, "End of /WHOIS list"]));
+                return reply;
+            }
+        };
+        let session = match session.lock() {
+            Ok(session) => session,
+            Err(_e) => return reply,
+        };
+        let m = session.id();
+        let target = session.nick().clone();
+        let joined: Vec<String> = session.channels().iter().cloned().collect();
+
+        reply.add_message(self.numeric(
+            "311",
+            nick,
+            &[
+                &target,
+                session.user(),
+                session.hostname(),
+                "*",
+                session.realname(),
+            ],
+        ));
+        let mut details = Vec::new();
+        details.push(self.numeric(
+            "312",
+            nick,
+            &[&target, &self.name, self.config.description()],
+        ));
+        if let Some(away) = session.away() {
+            details.push(self.numeric("301", nick, &[&target, away]));
+        }
+        if session.has_mode('o') {
+            details.push(self.numeric("313", nick, &[&target, "is an IRC operator"]));
+        }
+        if session.has_mode('B') {
+            details.push(self.numeric("335", nick, &[&target, "is a Bot"]));
+        }
+        if !session.account().is_empty() {
+            details.push(self.numeric(
+                "330",
+                nick,
+                &[&target, session.account(), "is logged in as"],
+            ));
+        }
+        if operator || m == id {
+            let host = format!(
+                "is connecting from *@{:} {:}",
+                session.hostname(),
+                session.connection().host()
+            );
+            details.push(self.numeric("378", nick, &[&target, &host]));
+        }
+        let idle = session.last_active().elapsed().as_secs().to_string();
+        let signon = session.signon().to_string();
+        details.push(self.numeric(
+            "317",
+            nick,
+            &[&target, &idle, &signon, "seconds idle, signon time"],
+        ));
+        drop(session);
+
+        let mut names = Vec::new();
+        if let Ok(channels) = self.channels.read() {
+            for name in joined {
+                if let Some(channel) = channels.get(&name) {
+                    let hidden = channel.has_mode('s') || channel.has_mode('p');
+                    if !hidden || operator || channel.has_member(id) {
+                        names.push(format!("{:}{:}", channel.prefix(m, all), channel.name()));
+                    }
+                }
+            }
+        }
+
+        // :<server> 319 <nick> <target> :<channels>\r\n
+        let overhead = self.name.len() + nick.len() + target.len() + 11;
+        let mut line = String::new();
+        for name in names {
+            if !line.is_empty() && overhead + line.len() + name.len() + 1 > BUFFER_SIZE {
+                reply.add_message(self.numeric("319", nick, &[&target, &line]));
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&name);
+        }
+        if !line.is_empty() {
+            reply.add_message(self.numeric("319", nick, &[&target, &line]));
+        }
+
+        for message in details {
+            reply.add_message(message);
+        }
+        reply.add_message(self.numeric("318", nick, &[&target, "End of /WHOIS list"]));
+        reply
+    }
+}
@@ -0,0 +1,491 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::casemapping::Casemapping;
+use crate::irc::message::Connection;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{ErrorKind, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+pub type SharedSessions = Arc<RwLock<Sessions>>;
+
+// Session holds everything the server knows about a single client connection
+// for as long as that connection stays open.
+pub struct Session {
+    account: String,
+    away: Option<String>,
+    cap_negotiating: bool,
+    cap_version: u32,
+    capabilities: HashSet<String>,
+    channels: HashSet<String>,
+    closed: Option<String>,
+    closed_time: Option<Instant>,
+    connected: Instant,
+    connection: Connection,
+    hostname: String,
+    id: u64,
+    last_active: Instant,
+    last_seen: Instant,
+    modes: BTreeSet<char>,
+    nick: String,
+    nick_changed: Option<Instant>,
+    partial: bool,
+    ping_sent: Option<Instant>,
+    realname: String,
+    registered: bool,
+    sendq: Vec<u8>,
+    signon: u64,
+    user: String,
+}
+
+impl Session {
+    // Returns the account the client is logged in to, or an empty string if
+    // it has not identified.
+    pub fn account(&self) -> &String {
+        &self.account
+    }
+
+    // Returns the away message, if the client has marked itself as away.
+    pub fn away(&self) -> Option<&String> {
+        self.away.as_ref()
+    }
+
+    pub fn add_capability(&mut self, capability: &str) {
+        self.capabilities.insert(capability.to_string());
+    }
+
+    pub fn add_channel(&mut self, channel: &str) {
+        self.channels.insert(channel.to_string());
+    }
+
+    pub fn add_mode(&mut self, mode: char) {
+        self.modes.insert(mode);
+    }
+
+    pub fn cap_negotiating(&self) -> bool {
+        self.cap_negotiating
+    }
+
+    pub fn cap_version(&self) -> u32 {
+        self.cap_version
+    }
+
+    pub fn capabilities(&self) -> &HashSet<String> {
+        &self.capabilities
+    }
+
+    pub fn channels(&self) -> &HashSet<String> {
+        &self.channels
+    }
+
+    // Send a final ERROR to the client and shut its connection down once the
+    // ERROR has been written. The Listener notices the shutdown and has the
+    // Service quit the session.
+    pub fn close(&mut self, reason: &str) {
+        if self.closed.is_some() {
+            return;
+        }
+        self.flush();
+        if self.closed.is_some() {
+            return;
+        }
+        self.closed = Some(reason.to_string());
+        self.closed_time = Some(Instant::now());
+
+        // Finish a line that was partly written so ERROR starts on a line of
+        // its own, but drop the lines that were never started.
+        let keep = if self.partial {
+            match self.sendq.iter().position(|b| *b == b'\n') {
+                Some(i) => i + 1,
+                None => self.sendq.len(),
+            }
+        } else {
+            0
+        };
+        self.sendq.truncate(keep);
+
+        let error = format!("ERROR :Closing Link: {:} ({:})\r\n", self.hostname, reason);
+        self.sendq.extend_from_slice(error.as_bytes());
+        self.flush();
+    }
+
+    pub fn closed(&self) -> bool {
+        self.closed.is_some()
+    }
+
+    // Returns why the connection was closed, if it has been.
+    pub fn closed_reason(&self) -> Option<&String> {
+        self.closed.as_ref()
+    }
+
+    // Returns when the connection was closed, if it has been.
+    pub fn closed_time(&self) -> Option<Instant> {
+        self.closed_time
+    }
+
+    pub fn connected(&self) -> Instant {
+        self.connected
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    // Write as much of the send queue as the connection will accept without
+    // blocking. Anything left is written when the Listener sees the
+    // connection become writable again. A closed connection is shut down
+    // once everything has been written.
+    pub fn flush(&mut self) {
+        while !self.sendq.is_empty() {
+            match self.connection.stream().write(&self.sendq) {
+                Ok(0) => break,
+                Ok(size) => {
+                    self.partial = self.sendq[size - 1] != b'\n';
+                    self.sendq.drain(..size);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                // If we can't write to stream shut it down.
+                Err(_e) => {
+                    if self.closed.is_none() {
+                        self.closed = Some("Write error".to_string());
+                    }
+                    self.sendq.clear();
+                }
+            }
+        }
+        if self.closed.is_some() && self.sendq.is_empty() {
+            let _ = self.connection.stream().shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.modes.contains(&mode)
+    }
+
+    pub fn hostname(&self) -> &String {
+        &self.hostname
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // Returns when the client last sent a message to another user or channel,
+    // which is what its idle time is measured from.
+    pub fn last_active(&self) -> Instant {
+        self.last_active
+    }
+
+    // Returns when data was last received from the client.
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+
+    pub fn modes(&self) -> String {
+        self.modes.iter().collect()
+    }
+
+    pub fn nick(&self) -> &String {
+        &self.nick
+    }
+
+    // Returns when the client was sent a PING that it has not yet answered.
+    pub fn ping_sent(&self) -> Option<Instant> {
+        self.ping_sent
+    }
+
+    // Returns when the client last changed its nick after registering.
+    pub fn nick_changed(&self) -> Option<Instant> {
+        self.nick_changed
+    }
+
+    // Returns the nick!user@host prefix used when relaying this client's
+    // messages to others.
+    pub fn prefix(&self) -> String {
+        format!("{:}!{:}@{:}", self.nick, self.user, self.hostname)
+    }
+
+    // Returns true when everything needed to register has been received but
+    // the welcome burst has not been sent yet.
+    pub fn ready(&self) -> bool {
+        !self.registered && !self.cap_negotiating && !self.nick.is_empty() && !self.user.is_empty()
+    }
+
+    // Returns how many bytes are waiting to be written to the client.
+    pub fn queued(&self) -> usize {
+        self.sendq.len()
+    }
+
+    pub fn realname(&self) -> &String {
+        &self.realname
+    }
+
+    pub fn registered(&self) -> bool {
+        self.registered
+    }
+
+    // Queue data for the client and try to write it straight away. Clients
+    // that let more than sendq bytes build up are disconnected.
+    pub fn send(&mut self, data: &[u8], sendq: usize) {
+        if self.closed.is_some() {
+            return;
+        }
+        self.sendq.extend_from_slice(data);
+        self.flush();
+        if self.sendq.len() > sendq {
+            self.close("SendQ exceeded");
+        }
+    }
+
+    // Shut the connection down straight away, dropping anything still queued.
+    pub fn shutdown(&mut self) {
+        self.sendq.clear();
+        let _ = self.connection.stream().shutdown(Shutdown::Both);
+    }
+
+    pub fn remove_capability(&mut self, capability: &str) {
+        self.capabilities.remove(capability);
+    }
+
+    pub fn remove_channel(&mut self, channel: &str) {
+        self.channels.remove(channel);
+    }
+
+    pub fn remove_mode(&mut self, mode: char) {
+        self.modes.remove(&mode);
+    }
+
+    pub fn set_account(&mut self, account: &str) {
+        self.account = account.to_string();
+    }
+
+    pub fn set_away(&mut self, away: Option<String>) {
+        self.away = away;
+    }
+
+    pub fn set_cap_negotiating(&mut self, cap_negotiating: bool) {
+        self.cap_negotiating = cap_negotiating;
+    }
+
+    pub fn set_cap_version(&mut self, cap_version: u32) {
+        self.cap_version = cap_version;
+    }
+
+    pub fn set_last_active(&mut self, last_active: Instant) {
+        self.last_active = last_active;
+    }
+
+    pub fn set_last_seen(&mut self, last_seen: Instant) {
+        self.last_seen = last_seen;
+    }
+
+    pub fn set_nick(&mut self, nick: &str) {
+        self.nick = nick.to_string();
+    }
+
+    pub fn set_nick_changed(&mut self, nick_changed: Option<Instant>) {
+        self.nick_changed = nick_changed;
+    }
+
+    pub fn set_ping_sent(&mut self, ping_sent: Option<Instant>) {
+        self.ping_sent = ping_sent;
+    }
+
+    pub fn set_realname(&mut self, realname: &str) {
+        self.realname = realname.to_string();
+    }
+
+    pub fn set_registered(&mut self, registered: bool) {
+        self.registered = registered;
+    }
+
+    pub fn set_signon(&mut self, signon: u64) {
+        self.signon = signon;
+    }
+
+    pub fn set_user(&mut self, user: &str) {
+        self.user = user.to_string();
+    }
+
+    // Returns when the client finished registering, in seconds since the Unix
+    // epoch.
+    pub fn signon(&self) -> u64 {
+        self.signon
+    }
+
+    // Use the client's nick as the reply target, or * if it has not set one
+    // yet.
+    pub fn target(&self) -> String {
+        if self.nick.is_empty() {
+            "*".to_string()
+        } else {
+            self.nick.clone()
+        }
+    }
+
+    pub fn user(&self) -> &String {
+        &self.user
+    }
+
+    pub fn new(connection: Connection) -> Session {
+        Session {
+            account: String::new(),
+            away: None,
+            cap_negotiating: false,
+            cap_version: 0,
+            capabilities: HashSet::new(),
+            channels: HashSet::new(),
+            closed: None,
+            closed_time: None,
+            connected: Instant::now(),
+            hostname: connection.host(),
+            id: connection.id(),
+            connection,
+            last_active: Instant::now(),
+            last_seen: Instant::now(),
+            modes: BTreeSet::new(),
+            nick: String::new(),
+            nick_changed: None,
+            partial: false,
+            ping_sent: None,
+            realname: String::new(),
+            registered: false,
+            sendq: Vec::new(),
+            signon: 0,
+            user: String::new(),
+        }
+    }
+}
+
+// Sessions is the registry of every open session, keyed by connection ID, along
+// with an index of the nicks they own. The Listener adds and removes sessions as
+// connections open and close while Workers look them up to service requests.
+//
+// Lock ordering: always take the Sessions lock before locking an individual
+// Session and never lock more than one Session at a time.
+pub struct Sessions {
+    casemapping: Casemapping,
+    closing: HashMap<u64, Arc<Mutex<Session>>>,
+    nicks: HashMap<String, u64>,
+    sessions: HashMap<u64, Arc<Mutex<Session>>>,
+}
+
+impl Sessions {
+    pub fn add(&mut self, session: Session) {
+        self.sessions
+            .insert(session.id(), Arc::new(Mutex::new(session)));
+    }
+
+    // Keep a session that has been removed until its final ERROR has been
+    // written, so the Listener can still flush it.
+    pub fn add_closing(&mut self, id: u64, session: Arc<Mutex<Session>>) {
+        self.closing.insert(id, session);
+    }
+
+    // Returns a removed session that is still writing its final ERROR.
+    pub fn closing(&self, id: u64) -> Option<Arc<Mutex<Session>>> {
+        self.closing.get(&id).cloned()
+    }
+
+    // Forget closing sessions that have finished writing and shut down any
+    // that have taken longer than timeout.
+    pub fn expire_closing(&mut self, timeout: Duration) {
+        self.closing.retain(|_id, session| match session.lock() {
+            Ok(mut session) => {
+                if session.queued() == 0 {
+                    return false;
+                }
+                let expired = match session.closed_time() {
+                    Some(closed_time) => closed_time.elapsed() > timeout,
+                    None => true,
+                };
+                if expired {
+                    session.shutdown();
+                }
+                !expired
+            }
+            Err(_e) => false,
+        });
+    }
+
+    // Find the session owning nick.
+    pub fn find(&self, nick: &str) -> Option<Arc<Mutex<Session>>> {
+        match self.nicks.get(&self.casemapping.fold(nick)) {
+            Some(id) => self.get(*id),
+            None => None,
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Mutex<Session>>> {
+        self.sessions.get(&id).cloned()
+    }
+
+    pub fn ids(&self) -> Vec<u64> {
+        self.sessions.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    // Returns the ID of the session owning nick.
+    pub fn owner(&self, nick: &str) -> Option<u64> {
+        self.nicks.get(&self.casemapping.fold(nick)).cloned()
+    }
+
+    // Remove a session and release its nick.
+    pub fn remove(&mut self, id: u64) -> Option<Arc<Mutex<Session>>> {
+        let session = self.sessions.remove(&id)?;
+        if let Ok(s) = session.lock() {
+            let key = self.casemapping.fold(s.nick());
+            if self.nicks.get(&key) == Some(&id) {
+                self.nicks.remove(&key);
+            }
+        }
+        Some(session)
+    }
+
+    // Move the nick index entry for id from old to new.
+    pub fn rename(&mut self, id: u64, old: &str, new: &str) {
+        if !old.is_empty() {
+            self.nicks.remove(&self.casemapping.fold(old));
+        }
+        self.nicks.insert(self.casemapping.fold(new), id);
+    }
+
+    // Changing the casemapping does not refold nicks already in the index so
+    // it must be set before any session claims a nick.
+    pub fn set_casemapping(&mut self, casemapping: Casemapping) {
+        self.casemapping = casemapping;
+    }
+
+    pub fn new() -> SharedSessions {
+        Arc::new(RwLock::new(Sessions {
+            casemapping: Casemapping::Rfc1459,
+            closing: HashMap::new(),
+            nicks: HashMap::new(),
+            sessions: HashMap::new(),
+        }))
+    }
+}
@@ -15,135 +15,153 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with Platform.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::irc::message::{Connection, Request};
+use crate::irc::message::{Buffer, Connection, Request};
 use crate::irc::service::Service;
-use std::collections::VecDeque;
+use crate::irc::session::{Session, Sessions, SharedSessions};
+use crate::irc::BUFFER_SIZE;
+use mio::net::TcpListener;
+use mio::{Events, Interest, Poll, Token};
+use std::collections::{HashMap, VecDeque};
 use std::io::{ErrorKind, Read};
-use std::net::TcpListener;
 use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread::{sleep, spawn, JoinHandle};
-use std::time;
+use std::time::Duration;
+
+const LISTENER: Token = Token(0);
+const POLL_TIMEOUT: Duration = Duration::from_millis(250);
+const TIMER_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type RequestQueue = Arc<(Mutex<VecDeque<(Connection, Request)>>, Condvar)>;
 
 pub struct Listener {
     bind_string: String,
-    request_queue: Arc<(Mutex<VecDeque<(Connection, Request)>>, Condvar)>,
+    request_queues: Vec<RequestQueue>,
     run: Arc<RwLock<bool>>,
+    sessions: SharedSessions,
 }
 
 impl Listener {
-    pub fn clone_request_queue(&self) -> Arc<(Mutex<VecDeque<(Connection, Request)>>, Condvar)> {
-        self.request_queue.clone()
+    pub fn clone_sessions(&self) -> SharedSessions {
+        self.sessions.clone()
+    }
+
+    // Create a request queue for a new Worker. Each connection is always
+    // dispatched to the same queue so its requests are handled in order.
+    pub fn new_request_queue(&mut self) -> RequestQueue {
+        let request_queue = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
+        self.request_queues.push(request_queue.clone());
+        request_queue
     }
 
     pub fn run(&self) -> JoinHandle<()> {
         // Clone self variables to be moved into new thread
         let bind_string = self.bind_string.clone();
-        let request_queue = self.request_queue.clone();
+        let request_queues = self.request_queues.clone();
         let run = self.run.clone();
+        let sessions = self.sessions.clone();
 
         spawn(move || {
+            if request_queues.is_empty() {
+                panic!("No request queues to dispatch requests to");
+            }
+
             // Create non-blocking TCP listener
-            let listener = match TcpListener::bind(bind_string.clone()) {
-                Ok(listener) => listener,
+            let mut listener = match bind_string.parse() {
+                Ok(addr) => match TcpListener::bind(addr) {
+                    Ok(listener) => listener,
+                    Err(_e) => {
+                        panic!("Could not bind to address: {:?}", bind_string);
+                    }
+                },
                 Err(_e) => {
-                    panic!("Could not bind to address: {:?}", bind_string);
+                    panic!("Could not parse address: {:?}", bind_string);
                 }
             };
-            listener
-                .set_nonblocking(true)
-                .expect("Cannot set non-blocking on listener");
 
-            // Sleep this long inside the following while loop
-            // to keep CPU cycles low.
-            let sleep_time = time::Duration::from_millis(60);
+            // Register the listener with the poller so we are only woken when
+            // there is something to accept or read
+            let mut poll = Poll::new().expect("Cannot create poll");
+            poll.registry()
+                .register(&mut listener, LISTENER, Interest::READABLE)
+                .expect("Cannot register listener");
+            let mut events = Events::with_capacity(1024);
 
-            // Use a VecDeque as a TCPStream queue
-            let mut streams = VecDeque::new();
+            // Open connections and their receive buffers keyed by connection ID
+            let mut connections: HashMap<u64, (Connection, Buffer)> = HashMap::new();
+
+            // Each accepted connection gets a stable ID for the life of its session.
+            // Token 0 belongs to the listener so IDs start at 1.
+            let mut next_id: u64 = 0;
 
             // While self.run equals true run the loop
             while match run.read() {
                 Ok(run) => *run,
                 Err(_e) => false,
             } {
-                // Accept new connections and queue them for later processing
-                match listener.accept() {
-                    Ok((s, _addr)) => match s.set_nonblocking(true) {
-                        Ok(_r) => {
-                            streams.push_back(s);
-                        }
-                        Err(_e) => {}
-                    },
-                    Err(_e) => {}
+                // Wait for events, waking periodically to check self.run
+                match poll.poll(&mut events, Some(POLL_TIMEOUT)) {
+                    Ok(_r) => {}
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        panic!("Cannot poll for events: {:?}", e);
+                    }
                 }
 
-                // Create index counter for while loop
-                let mut i = 0;
-                while i < streams.len() {
-                    // Remove the first stream from the top of the stream queue
-                    let mut s = match streams.pop_front() {
-                        Some(s) => s,
-                        None => {
-                            break;
-                        }
-                    };
-
-                    // Create new IRC request
-                    let mut request = Request::new();
-
-                    // Attempt to read data from stream
-                    match s.read(request.data()) {
-                        Ok(size) => {
-                            // Dead streams return valid data but with 0 data size
-                            // skip these streams and do not add them back to the
-                            // streams queue
-                            if size > 0 {
-                                // Queue the request for IRC worker threads and notify them
-                                let (request_queue, cvar) = &*request_queue;
-                                let s_clone = match s.try_clone() {
-                                    Ok(s_clone) => s_clone,
-                                    Err(_e) => {
-                                        i = i + 1;
-                                        continue;
-                                    }
-                                };
-                                let c = Connection::new(s_clone);
-                                match request_queue.lock() {
-                                    Ok(mut request_queue) => {
-                                        request_queue.push_back((c, request));
-                                        drop(request_queue);
-                                        cvar.notify_one();
-                                    }
-                                    Err(_e) => {
-                                        match run.write() {
-                                            Ok(mut run) => {
-                                                *run = false;
-                                            }
-                                            Err(_e) => {}
-                                        }
-                                        break;
-                                    }
+                for event in events.iter() {
+                    match event.token() {
+                        // Accept every pending connection and create their sessions
+                        LISTENER => loop {
+                            let mut stream = match listener.accept() {
+                                Ok((stream, _addr)) => stream,
+                                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                                Err(_e) => break,
+                            };
+                            next_id += 1;
+                            let token = Token(next_id as usize);
+                            let interest = Interest::READABLE | Interest::WRITABLE;
+                            if poll
+                                .registry()
+                                .register(&mut stream, token, interest)
+                                .is_err()
+                            {
+                                continue;
+                            }
+                            let connection = Connection::new(next_id, stream);
+                            if let Ok(mut sessions) = sessions.write() {
+                                sessions.add(Session::new(connection.clone()));
+                                connections.insert(next_id, (connection, Buffer::new()));
+                            }
+                        },
+                        Token(token) => {
+                            let id = token as u64;
+
+                            // Write whatever the session could not send earlier
+                            if event.is_writable() {
+                                flush_session(&sessions, id);
+                            }
+
+                            if !event.is_readable() && !event.is_read_closed() {
+                                continue;
+                            }
+                            let result = match connections.get_mut(&id) {
+                                Some((connection, buffer)) => {
+                                    receive(&request_queues, connection, buffer)
                                 }
+                                None => continue,
+                            };
 
-                                // Put the stream on the back of the stream queue for
-                                // later processing
-                                streams.push_back(s);
+                            // Let the Service quit the session behind any
+                            // requests still queued for it
+                            if let Err(reason) = result {
+                                if let Some((connection, _buffer)) = connections.remove(&id) {
+                                    let mut request = Request::new();
+                                    request.set_disconnect(reason);
+                                    dispatch(&request_queues, connection, request);
+                                }
                             }
                         }
-                        // If stream would normally block then put stream back on
-                        // the stream queue for later processing
-                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                            streams.push_back(s);
-                        }
-                        Err(e) => {
-                            println!("I got error! {:?}", e);
-                        }
                     }
-
-                    i = i + 1;
                 }
-
-                // Sleep for low CPU cycles
-                sleep(sleep_time);
             }
         })
     }
@@ -164,14 +182,113 @@ impl Listener {
     pub fn new() -> Listener {
         Listener {
             bind_string: String::new(),
-            request_queue: Arc::new((Mutex::new(VecDeque::new()), Condvar::new())),
+            request_queues: Vec::new(),
             run: Arc::new(RwLock::new(true)),
+            sessions: Sessions::new(),
+        }
+    }
+}
+
+// Queue a request for the IRC worker thread that owns this connection and
+// notify it.
+fn dispatch(request_queues: &[RequestQueue], connection: Connection, request: Request) {
+    let shard = connection.id() as usize % request_queues.len();
+    let (request_queue, cvar) = &*request_queues[shard];
+    if let Ok(mut request_queue) = request_queue.lock() {
+        request_queue.push_back((connection, request));
+        drop(request_queue);
+        cvar.notify_one();
+    }
+}
+
+// Flush the send queue of a session whose connection has become writable,
+// including one that is only left to write its final ERROR.
+fn flush_session(sessions: &SharedSessions, id: u64) {
+    let session = match sessions.read() {
+        Ok(sessions) => sessions.get(id).or_else(|| sessions.closing(id)),
+        Err(_e) => None,
+    };
+    if let Some(session) = session {
+        if let Ok(mut session) = session.lock() {
+            session.flush();
+        }
+    }
+}
+
+// Read everything available from a connection and dispatch each complete
+// line. Streams are edge triggered so reading continues until it would block.
+// Returns the reason once the connection has closed.
+fn receive(
+    request_queues: &[RequestQueue],
+    connection: &Connection,
+    buffer: &mut Buffer,
+) -> Result<(), &'static str> {
+    let mut data = [0_u8; BUFFER_SIZE];
+    loop {
+        match connection.stream().read(&mut data) {
+            // Dead streams return valid data but with 0 data size
+            Ok(0) => return Err("Connection closed"),
+            Ok(size) => {
+                // Only complete lines are turned into a request, anything
+                // else stays buffered until the next read
+                buffer.extend(&data[..size]);
+                if let Some(request) = buffer.request() {
+                    dispatch(request_queues, connection.clone(), request);
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(_e) => return Err("Read error"),
+        }
+    }
+}
+
+// Timer wakes up periodically to run the Service's housekeeping, such as
+// pinging idle clients, disconnecting ones that have timed out and sending
+// more of any LIST that did not fit in a client's SendQ.
+pub struct Timer {
+    run: Arc<RwLock<bool>>,
+    service: Arc<Service>,
+}
+
+impl Timer {
+    pub fn run(&self) -> JoinHandle<()> {
+        // Clone self variables to be moved into new thread.
+        let run = self.run.clone();
+        let service = self.service.clone();
+
+        spawn(move || {
+            // While self.run equals true run the loop.
+            while match run.read() {
+                Ok(run) => *run,
+                Err(_e) => false,
+            } {
+                sleep(TIMER_INTERVAL);
+                service.check_timeouts();
+                service.resume_lists();
+            }
+        })
+    }
+
+    pub fn stop(&self) {
+        match self.run.write() {
+            Ok(mut run) => {
+                *run = false;
+            }
+            Err(_e) => {}
+        }
+    }
+
+    pub fn new(service: Arc<Service>) -> Timer {
+        Timer {
+            run: Arc::new(RwLock::new(true)),
+            service,
         }
     }
 }
 
 pub struct Worker {
-    request_queue: Arc<(Mutex<VecDeque<(Connection, Request)>>, Condvar)>,
+    request_queue: RequestQueue,
     run: Arc<RwLock<bool>>,
     service: Arc<Service>,
 }
@@ -201,14 +318,11 @@ impl Worker {
                                 }
                             }
                         }
-                        match request_queue.pop_front() {
-                            Some((connection, mut request)) => {
-                                // Drop the request_queue lock
-                                drop(request_queue);
-                                // Call service.reply to do the actual work.
-                                service.reply(&connection, &mut request);
-                            }
-                            None => {}
+                        if let Some((connection, mut request)) = request_queue.pop_front() {
+                            // Drop the request_queue lock
+                            drop(request_queue);
+                            // Call service.reply to do the actual work.
+                            service.reply(&connection, &mut request);
                         }
                     }
                     Err(_e) => {
@@ -231,14 +345,11 @@ impl Worker {
         cvar.notify_all();
     }
 
-    pub fn new(
-        request_queue: Arc<(Mutex<VecDeque<(Connection, Request)>>, Condvar)>,
-        service: Arc<Service>,
-    ) -> Worker {
+    pub fn new(request_queue: RequestQueue, service: Arc<Service>) -> Worker {
         Worker {
-            request_queue: request_queue,
+            request_queue,
             run: Arc::new(RwLock::new(true)),
-            service: service,
+            service,
         }
     }
 }
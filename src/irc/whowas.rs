@@ -0,0 +1,99 @@
+// Copyright 2020 Jonathan Windle
+
+// This file is part of Platform.
+
+// Platform is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Platform is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Platform.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::irc::casemapping::Casemapping;
+use std::collections::VecDeque;
+
+// WhowasEntry records who was using a nick when they quit or changed it.
+pub struct WhowasEntry {
+    hostname: String,
+    nick: String,
+    realname: String,
+    time: u64,
+    user: String,
+}
+
+impl WhowasEntry {
+    pub fn hostname(&self) -> &String {
+        &self.hostname
+    }
+
+    pub fn nick(&self) -> &String {
+        &self.nick
+    }
+
+    pub fn realname(&self) -> &String {
+        &self.realname
+    }
+
+    // Returns when the nick was given up, in seconds since the Unix epoch.
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    pub fn user(&self) -> &String {
+        &self.user
+    }
+
+    pub fn new(nick: &str, user: &str, hostname: &str, realname: &str, time: u64) -> WhowasEntry {
+        WhowasEntry {
+            hostname: hostname.to_string(),
+            nick: nick.to_string(),
+            realname: realname.to_string(),
+            time,
+            user: user.to_string(),
+        }
+    }
+}
+
+// Whowas is a bounded history of nicks that are no longer in use. Once full
+// the oldest entry is dropped to make room for each new one.
+pub struct Whowas {
+    casemapping: Casemapping,
+    entries: VecDeque<WhowasEntry>,
+    length: usize,
+}
+
+impl Whowas {
+    pub fn add(&mut self, entry: WhowasEntry) {
+        if self.length == 0 {
+            return;
+        }
+        while self.entries.len() >= self.length {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    // Returns the entries for a nick, newest first.
+    pub fn find(&self, nick: &str) -> Vec<&WhowasEntry> {
+        let nick = self.casemapping.fold(nick);
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| self.casemapping.fold(entry.nick()) == nick)
+            .collect()
+    }
+
+    pub fn new(casemapping: Casemapping, length: usize) -> Whowas {
+        Whowas {
+            casemapping,
+            entries: VecDeque::new(),
+            length,
+        }
+    }
+}
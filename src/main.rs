@@ -15,17 +15,24 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with Platform.  If not, see <https://www.gnu.org/licenses/>.
 
+extern crate mio;
 extern crate num_cpus;
+extern crate unicode_normalization;
 
 mod irc;
 
+use std::sync::Arc;
+
 fn main() {
+    let config = Arc::new(irc::Config::new());
     let mut listener = irc::Listener::new();
-    let service = irc::Service::new();
+    let service = irc::Service::new(listener.clone_sessions(), config.clone());
     for _ in 0..num_cpus::get() {
-        let worker = irc::Worker::new(listener.clone_request_queue(), service.clone());
+        let worker = irc::Worker::new(listener.new_request_queue(), service.clone());
         let _ = worker.run();
     }
+    let timer = irc::Timer::new(service.clone());
+    let _ = timer.run();
     listener.set_bind_string("127.0.0.1:6667".to_string());
     let t = listener.run();
     let _ = t.join();